@@ -4,35 +4,46 @@
 #[macro_use]
 extern crate alloc;
 
-use core::fmt::Write as _;
-use core::iter;
+#[cfg(feature = "std")]
+extern crate std;
+
+mod ornaments;
+mod transposition;
+mod parse;
+mod xml;
+
+use core::fmt;
+use core::{iter, slice};
 
 use alloc::vec::Vec;
-use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
 use alloc::string::String;
 
 pub use amm_sdk; // re-export for lib users
+pub use transposition::Transposition;
+pub use parse::{parse_project, ParsedProject, ParseError};
 
 use amm_sdk::Composition;
-use amm_sdk::note::{Note, DurationType, Duration, Accidental};
-use amm_sdk::context::{Key, Tempo};
+use amm_sdk::note::{Note, Pitch, DurationType, Duration, Accidental};
+use amm_sdk::context::{Key, Tempo, Dynamic, TimeSignature, TimeSignatureType};
 use amm_sdk::modification::{PhraseModificationType, NoteModificationType, SectionModificationType, DirectionType, NoteModification, ChordModificationType};
-use amm_sdk::structure::{Part, Section, Staff, PartContent, SectionContent, StaffContent, ChordContent, Phrase, PhraseContent};
+use amm_sdk::structure::{Chord, Part, Section, Staff, PartContent, SectionContent, StaffContent, ChordContent, Phrase, PhraseContent, MultiVoice, MultiVoiceContent};
 
-fn xml_escape(input: &str) -> String {
-    let mut result = String::with_capacity(input.len());
-    for c in input.chars() {
-        match c {
-            '&' => result.push_str("&amp;"),
-            '<' => result.push_str("&lt;"),
-            '>' => result.push_str("&gt;"),
-            '\'' => result.push_str("&apos;"),
-            '"' => result.push_str("&quot;"),
-            '\n' => result.push_str("&#xD;"),
-            _ => result.push(c),
-        }
+use ornaments::{Ornament, diatonic_neighbor, slide};
+use xml::{Xml, Depth, Fragment, Digest};
+
+const TICKS_PER_WHOLE: u32 = 2048;
+const DYNAMIC_STEP: f64 = 10.0; // volume change of crescendos/decrescendos with no explicit target
+const TEMPO_RATIO: f64 = 1.25; // tempo change of accelerandos/ritardandos with no following explicit tempo
+
+fn measure_length(time_signature: &TimeSignature) -> f64 {
+    match time_signature.signature {
+        TimeSignatureType::None => 0.0,
+        _ => f64::from(time_signature.numerator) / f64::from(time_signature.denominator),
     }
-    result
+}
+fn dynamic_volume(dynamic: &Dynamic) -> f64 {
+    (100.0 * f64::from(dynamic.value())).round()
 }
 fn quarter_note_tempo(tempo: &Tempo) -> f64 {
     tempo.beats_per_minute as f64 * (tempo.base_note.value() / Duration::new(DurationType::Quarter, 0).value())
@@ -43,48 +54,247 @@ pub enum TranslateError {
     CyclicStructure,
     UnsupportedDuration { duration: Duration },
     UnsupportedTuplet { num_beats: u8, into_beats: u8 },
+    UnsupportedTremolo { relative_speed: u8 },
+    WriteFailed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IgnoredElement {
+    Direction(DirectionType),
+    NoteModification(NoteModificationType),
+    ChordModification(ChordModificationType),
+    PhraseModification(PhraseModificationType),
+    SectionModification(SectionModificationType),
+    Duration(Duration), // played as the nearest duration NetsBlox can express
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslateWarning {
+    pub path: Vec<String>,
+    pub element: IgnoredElement,
+}
+impl fmt::Display for TranslateWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, value) = match &self.element {
+            IgnoredElement::Direction(x) => ("direction", format!("{x:?}")),
+            IgnoredElement::NoteModification(x) => ("note modification", format!("{x:?}")),
+            IgnoredElement::ChordModification(x) => ("chord modification", format!("{x:?}")),
+            IgnoredElement::PhraseModification(x) => ("phrase modification", format!("{x:?}")),
+            IgnoredElement::SectionModification(x) => ("section modification", format!("{x:?}")),
+            IgnoredElement::Duration(x) => ("duration", format!("{x:?}")),
+        };
+        write!(f, "{}: ignored {kind} {value}", self.path.join("/"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Translation {
+    pub project: String,
+    pub warnings: Vec<TranslateWarning>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GraceNoteStyle {
+    #[default]
+    Notated,
+    Acciaccatura,
+    Appoggiatura,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FermataStyle {
+    #[default]
+    Extend,
+    Wait,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GlissandoStyle {
+    #[default]
+    Off,
+    Chromatic,
+    Diatonic,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpriteLayout {
+    #[default]
+    Stacked,
+    Row,
+    Column,
+}
+
+#[derive(Debug, Clone)]
+pub struct TranslateOptions {
+    pub room_name: Option<String>,
+    pub role_name: String,
+    pub stage_width: u32,
+    pub stage_height: u32,
+    pub sprite_layout: SpriteLayout,
+    pub instrument: Option<String>,
+    pub part_instruments: BTreeMap<String, String>,
+    pub part_programs: BTreeMap<String, u8>,
+    pub instrument_keywords: Vec<(String, String)>,
+    pub section_blocks: bool,
+    pub phrase_blocks: bool,
+    pub grace_notes: GraceNoteStyle,
+    pub measure_numbers: bool,
+    pub fermata_factor: f64,
+    pub fermata_style: FermataStyle,
+    pub glissandos: GlissandoStyle,
+    pub strict_durations: bool,
+    pub transposition: Transposition,
+    pub part_transpositions: BTreeMap<String, Transposition>,
+    pub pretty: bool,
+}
+impl Default for TranslateOptions {
+    fn default() -> Self {
+        Self {
+            room_name: None,
+            role_name: "myRole".into(),
+            stage_width: 480,
+            stage_height: 360,
+            sprite_layout: SpriteLayout::Stacked,
+            instrument: None,
+            part_instruments: BTreeMap::new(),
+            part_programs: BTreeMap::new(),
+            instrument_keywords: Vec::new(),
+            section_blocks: true,
+            phrase_blocks: false,
+            grace_notes: GraceNoteStyle::Notated,
+            measure_numbers: false,
+            fermata_factor: 2.0,
+            fermata_style: FermataStyle::Extend,
+            glissandos: GlissandoStyle::Off,
+            strict_durations: false,
+            transposition: Transposition::default(),
+            part_transpositions: BTreeMap::new(),
+            pretty: false,
+        }
+    }
+}
+impl TranslateOptions {
+    pub fn with_room_name(mut self, room_name: impl Into<String>) -> Self {
+        self.room_name = Some(room_name.into());
+        self
+    }
+    pub fn with_role_name(mut self, role_name: impl Into<String>) -> Self {
+        self.role_name = role_name.into();
+        self
+    }
+    pub fn with_stage_size(mut self, width: u32, height: u32) -> Self {
+        self.stage_width = width;
+        self.stage_height = height;
+        self
+    }
+    pub fn with_sprite_layout(mut self, sprite_layout: SpriteLayout) -> Self {
+        self.sprite_layout = sprite_layout;
+        self
+    }
+    pub fn with_instrument(mut self, instrument: impl Into<String>) -> Self {
+        self.instrument = Some(instrument.into());
+        self
+    }
+    pub fn with_part_instrument(mut self, part_name: impl Into<String>, instrument: impl Into<String>) -> Self {
+        self.part_instruments.insert(part_name.into(), instrument.into());
+        self
+    }
+    pub fn with_part_program(mut self, part_name: impl Into<String>, program: u8) -> Self {
+        self.part_programs.insert(part_name.into(), program);
+        self
+    }
+    pub fn with_instrument_keyword(mut self, keyword: impl Into<String>, instrument: impl Into<String>) -> Self {
+        self.instrument_keywords.push((keyword.into(), instrument.into()));
+        self
+    }
+    pub fn with_section_blocks(mut self, section_blocks: bool) -> Self {
+        self.section_blocks = section_blocks;
+        self
+    }
+    pub fn with_phrase_blocks(mut self, phrase_blocks: bool) -> Self {
+        self.phrase_blocks = phrase_blocks;
+        self
+    }
+    pub fn with_grace_notes(mut self, grace_notes: GraceNoteStyle) -> Self {
+        self.grace_notes = grace_notes;
+        self
+    }
+    pub fn with_measure_numbers(mut self, measure_numbers: bool) -> Self {
+        self.measure_numbers = measure_numbers;
+        self
+    }
+    pub fn with_fermata_factor(mut self, fermata_factor: f64) -> Self {
+        self.fermata_factor = fermata_factor;
+        self
+    }
+    pub fn with_fermata_style(mut self, fermata_style: FermataStyle) -> Self {
+        self.fermata_style = fermata_style;
+        self
+    }
+    pub fn with_glissandos(mut self, glissandos: GlissandoStyle) -> Self {
+        self.glissandos = glissandos;
+        self
+    }
+    pub fn with_strict_durations(mut self, strict_durations: bool) -> Self {
+        self.strict_durations = strict_durations;
+        self
+    }
+    pub fn with_transposition(mut self, transposition: Transposition) -> Self {
+        self.transposition = transposition;
+        self
+    }
+    pub fn with_part_transposition(mut self, part_name: impl Into<String>, transposition: Transposition) -> Self {
+        self.part_transpositions.insert(part_name.into(), transposition);
+        self
+    }
+    pub fn with_pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum Mod {
-    Accent, Staccato, TurnUpper, TurnLower,
+    Accent, Staccato, TurnUpper, TurnLower, Legato,
 }
 #[derive(Default)]
 struct Modifiers {
-    stack: Vec<Vec<Mod>>,
+    stack: Vec<(Vec<Mod>, Depth)>,
     active: BTreeSet<Mod>,
 }
 
 macro_rules! check_modifiers_invariants {
     ($self:ident) => {{
-        debug_assert!($self.stack.iter().all(|x| !x.is_empty()));
-        debug_assert!($self.stack.iter().map(|x| x.len()).sum::<usize>() == $self.active.len());
-        debug_assert!($self.stack.iter().flat_map(|x| x.iter().copied()).collect::<BTreeSet<_>>() == $self.active);
+        debug_assert!($self.stack.iter().all(|x| !x.0.is_empty()));
+        debug_assert!($self.stack.iter().map(|x| x.0.len()).sum::<usize>() == $self.active.len());
+        debug_assert!($self.stack.iter().flat_map(|x| x.0.iter().copied()).collect::<BTreeSet<_>>() == $self.active);
     }};
 }
 
 impl Modifiers {
-    fn set(&mut self, new_active: &BTreeSet<Mod>, output: &mut String) {
+    fn set(&mut self, new_active: &BTreeSet<Mod>, output: &mut Xml) {
         check_modifiers_invariants!(self);
 
         while !self.active.is_subset(new_active) {
-            for x in self.stack.pop().unwrap() {
+            let (mods, depth) = self.stack.pop().unwrap();
+            for x in mods {
                 self.active.remove(&x);
             }
-            write!(output, r#"</script></block>"#).unwrap();
+            output.close_to(depth);
         }
 
         let new = (new_active - &self.active).into_iter().collect::<Vec<_>>();
 
         if !new.is_empty() {
-            write!(output, r#"<block s="noteMod"><list>"#).unwrap();
+            let depth = output.depth();
+            output.block("noteMod").open("list", &[]);
             for x in new.iter() {
                 self.active.insert(*x);
-                write!(output, r#"<l><option>{x:?}</option></l>"#).unwrap();
+                output.open("l", &[]).leaf("option", &[], format_args!("{x:?}")).close();
             }
-            write!(output, r#"</list><script>"#).unwrap();
+            output.close().open("script", &[]);
 
-            self.stack.push(new);
+            self.stack.push((new, depth));
         }
 
         check_modifiers_invariants!(self);
@@ -92,28 +302,171 @@ impl Modifiers {
     fn unwind_point(&self) -> usize {
         self.stack.len()
     }
-    fn unwind_to(&mut self, point: usize, output: &mut String) {
+    fn unwind_to(&mut self, point: usize, output: &mut Xml) {
         check_modifiers_invariants!(self);
 
         while self.stack.len() > point {
-            for x in self.stack.pop().unwrap() {
+            let (mods, depth) = self.stack.pop().unwrap();
+            for x in mods {
                 self.active.remove(&x);
             }
-            write!(output, r#"</script></block>"#).unwrap();
+            output.close_to(depth);
         }
 
         check_modifiers_invariants!(self);
     }
 }
 
-struct Context {
+struct TiedChord {
+    pitches: Vec<String>,
+    notes_xml: Fragment,
+    durations: Vec<Fragment>,
+    mods: BTreeSet<Mod>,
+    comment: Fragment,
+    after: Fragment,
+}
+
+#[derive(Clone)]
+struct Measures {
+    number: usize,
+    start: f64,
+    length: f64,
+    commented: usize,
+}
+impl Measures {
+    fn new(time_signature: &TimeSignature) -> Self {
+        Self { number: 1, start: 0.0, length: measure_length(time_signature), commented: 0 }
+    }
+    fn advance_to(&mut self, position: f64) {
+        // small tolerance since positions accumulate floating point error
+        while self.length > 0.0 && position >= self.start + self.length - 1e-9 {
+            self.start += self.length;
+            self.number += 1;
+        }
+    }
+}
+
+// a gradual change (of volume or tempo) over a span of positions (in whole notes) within the part
+struct Ramp {
+    start: f64,
+    end: f64,
+    from: f64,
+    to: f64,
+    hairpin: bool,
+}
+impl Ramp {
+    fn value_at(&self, position: f64) -> f64 {
+        let progress = if self.end > self.start { ((position - self.start) / (self.end - self.start)).clamp(0.0, 1.0) } else { 1.0 };
+        let progress = if self.hairpin { 1.0 - (2.0 * progress - 1.0).abs() } else { progress };
+        (self.from + (self.to - self.from) * progress).round()
+    }
+    fn final_value(&self) -> f64 {
+        if self.hairpin { self.from } else { self.to }
+    }
+}
+
+// the project is written in several passes over the same parts, each of which plays out identically
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Count, // hashes phrase bodies to find the repeated ones (only with phrase blocks)
+    Sprites, // writes the sprites and decides which blocks to define
+    Blocks, // writes the definition of one block (one pass per block)
+}
+
+// a block name and whether this is the occurrence that defines it
+type SharedBlock = (String, bool);
+
+// block bodies are deduplicated by hash alone without comparing the bodies (see Digest), and each pass records its decisions for the passes after it
+#[derive(Default, Clone)]
+struct SharedBlocks {
+    names: BTreeSet<String>,
+    hashes: BTreeMap<u128, String>,
+    phrase_hashes: VecDeque<Option<u128>>,
+    phrase_counts: BTreeMap<u128, usize>,
+    phrases: VecDeque<Option<SharedBlock>>,
+    sections: VecDeque<SharedBlock>,
+}
+impl SharedBlocks {
+    fn block(&mut self, hash: u128, name: &str) -> SharedBlock {
+        if let Some(x) = self.hashes.get(&hash) {
+            return (x.clone(), false);
+        }
+        let name = iter::once(String::new()).chain((2usize..).map(|x| format!(" {x}"))).map(|x| format!("{name}{x}")).find(|x| !self.names.contains(x)).unwrap();
+        self.names.insert(name.clone());
+        self.hashes.insert(hash, name.clone());
+        (name, true)
+    }
+}
+
+struct Context<'a, 'w> {
+    options: &'a TranslateOptions,
+    pass: Pass,
+    defining: String, // the one block whose definition this (blocks) pass writes
+    sink: Option<Xml<'w>>,
     modifiers: Modifiers,
     sections: BTreeSet<*const Section>,
     staffs: BTreeSet<*const Staff>,
     phrases: BTreeSet<*const Phrase>,
+    multivoices: BTreeSet<*const MultiVoice>,
     starting_key: Key,
     starting_tempo: Tempo,
-    blocks: BTreeMap<String, String>,
+    key: Key,
+    starting_time_signature: TimeSignature,
+    blocks: SharedBlocks,
+    grace_notes: Vec<Vec<Note>>,
+    tie: Option<TiedChord>,
+    glide: Option<(Note, IgnoredElement)>,
+    next_dynamic: Option<Dynamic>,
+    octave_shift: i8,
+    transposition: Transposition,
+    phrase_mods: Vec<Mod>,
+    position: f64,
+    tuplet_ratio: f64,
+    volume: f64,
+    target_volume: f64,
+    volume_ramp: Option<Ramp>,
+    tempo: f64,
+    target_tempo: f64,
+    tempo_ramp: Option<Ramp>,
+    tempo_scale: f64,
+    tempo_restore: bool, // the tempo is still scaled for the last note played
+    time_signature: TimeSignature,
+    measures: Measures,
+    iterations: Vec<String>,
+    path: Vec<String>,
+    warnings: Vec<TranslateWarning>,
+}
+impl<'w> Context<'_, 'w> {
+    // where to write the body of a block, which is only kept by the occurrence that defines the block of this pass
+    fn block_output(&mut self, (name, define): &SharedBlock) -> Option<Xml<'w>> {
+        match *define && self.pass == Pass::Blocks && *name == self.defining {
+            true => self.sink.take(),
+            false => None,
+        }
+    }
+    // comment for the first block of each measure (if enabled)
+    fn measure_comment(&mut self, position: f64) -> Fragment {
+        self.measures.advance_to(position);
+        if !self.options.measure_numbers || self.measures.length <= 0.0 || self.measures.commented == self.measures.number {
+            return Fragment::default();
+        }
+        self.measures.commented = self.measures.number;
+        let mut res = Xml::default();
+        res.leaf("comment", &[("w", &90), ("collapsed", &false)], format_args!("measure {}", self.measures.number));
+        res.finish()
+    }
+    fn sounding_key(&self) -> Key {
+        self.transposition.key(&self.key)
+    }
+    fn sounding_note(&self, note: Note) -> Note {
+        self.transposition.note(shift_octaves(note, self.octave_shift), &self.key)
+    }
+    fn tempo_at(&self, position: f64) -> f64 {
+        self.tempo_ramp.as_ref().map(|x| x.value_at(position)).unwrap_or(self.target_tempo)
+    }
+    fn warn(&mut self, element: IgnoredElement) {
+        self.warnings.push(TranslateWarning { path: self.path.clone(), element });
+    }
 }
 
 fn half_duration_type(duration_type: DurationType) -> Option<DurationType> {
@@ -135,236 +488,1270 @@ fn half_duration_type(duration_type: DurationType) -> Option<DurationType> {
         DurationType::TwoThousandFortyEighth => None,
     }
 }
-fn parse_duration(duration: Duration) -> Result<String, TranslateError> {
+// tremolo strokes are counted from an unmeasured eighth note (one stroke) downward
+fn tremolo_unit(relative_speed: u8) -> Result<DurationType, TranslateError> {
+    let mut res = DurationType::Quarter;
+    for _ in 0..relative_speed {
+        res = half_duration_type(res).ok_or(TranslateError::UnsupportedTremolo { relative_speed })?;
+    }
+    match parse_duration(Duration::new(res, 0)) {
+        Ok(_) => Ok(res),
+        Err(_) => Err(TranslateError::UnsupportedTremolo { relative_speed }),
+    }
+}
+fn parse_duration(duration: Duration) -> Result<Fragment, TranslateError> {
+    let mut res = Xml::default();
     let dots = match duration.dots {
         0 => "",
         1 => "Dotted",
         2 => "DottedDotted",
         x => {
-            let mut res = String::from(r#"<block s="tieDuration"><list>"#);
+            res.block("tieDuration").open("list", &[]);
             let mut t = duration.value;
             for _ in 2..x {
-                res += &parse_duration(Duration::new(t, 0)).map_err(|_| TranslateError::UnsupportedDuration { duration })?;
-                t = half_duration_type(t).ok_or_else(|| TranslateError::UnsupportedDuration { duration })?;
+                res.append(&parse_duration(Duration::new(t, 0)).map_err(|_| TranslateError::UnsupportedDuration { duration })?);
+                t = half_duration_type(t).ok_or(TranslateError::UnsupportedDuration { duration })?;
             }
-            res += &parse_duration(Duration::new(t, 2)).map_err(|_| TranslateError::UnsupportedDuration { duration })?;
-            res += "</list></block>";
-            return Ok(res);
+            res.append(&parse_duration(Duration::new(t, 2)).map_err(|_| TranslateError::UnsupportedDuration { duration })?);
+            res.close().close();
+            return Ok(res.finish());
         }
     };
-    Ok(match duration.value {
-        DurationType::Maxima => format!(r#"<block s="tieDuration"><list><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l></list></block>"#),
-        DurationType::Long => format!(r#"<block s="tieDuration"><list><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l><l>{dots}Whole</l></list></block>"#),
-        DurationType::Breve => format!(r#"<block s="tieDuration"><list><l>{dots}Whole</l><l>{dots}Whole</l></list></block>"#),
-        DurationType::Whole => format!("<l>{dots}Whole</l>"),
-        DurationType::Half => format!("<l>{dots}Half</l>"),
-        DurationType::Quarter => format!("<l>{dots}Quarter</l>"),
-        DurationType::Eighth => format!("<l>{dots}Eighth</l>"),
-        DurationType::Sixteenth => format!("<l>{dots}Sixteenth</l>"),
-        DurationType::ThirtySecond => format!("<l>{dots}ThirtySecond</l>"),
-        DurationType::SixtyFourth => format!("<l>{dots}SixtyFourth</l>"),
+    let (name, count) = match duration.value {
+        DurationType::Maxima => ("Whole", 8),
+        DurationType::Long => ("Whole", 4),
+        DurationType::Breve => ("Whole", 2),
+        DurationType::Whole => ("Whole", 1),
+        DurationType::Half => ("Half", 1),
+        DurationType::Quarter => ("Quarter", 1),
+        DurationType::Eighth => ("Eighth", 1),
+        DurationType::Sixteenth => ("Sixteenth", 1),
+        DurationType::ThirtySecond => ("ThirtySecond", 1),
+        DurationType::SixtyFourth => ("SixtyFourth", 1),
         _ => return Err(TranslateError::UnsupportedDuration { duration }),
-    })
+    };
+    match count {
+        1 => _ = res.l(format_args!("{dots}{name}")),
+        _ => {
+            res.block("tieDuration").open("list", &[]);
+            for _ in 0..count {
+                res.l(format_args!("{dots}{name}"));
+            }
+            res.close().close();
+        }
+    }
+    Ok(res.finish())
+}
+fn duration_ticks(duration: Duration) -> u32 {
+    (duration.value() * TICKS_PER_WHOLE as f64).round() as u32
+}
+fn ticks_duration(ticks: u32) -> Option<Fragment> {
+    const DURATIONS: [(&str, u32); 7] = [("Whole", 1), ("Half", 2), ("Quarter", 4), ("Eighth", 8), ("Sixteenth", 16), ("ThirtySecond", 32), ("SixtyFourth", 64)];
+
+    let mut parts = vec![];
+    let mut remaining = ticks;
+    while remaining != 0 {
+        let (dots, name, value) = DURATIONS.iter().flat_map(|&(name, div)| {
+            let value = TICKS_PER_WHOLE / div;
+            [("DottedDotted", name, value * 7 / 4), ("Dotted", name, value * 3 / 2), ("", name, value)]
+        }).find(|x| x.2 <= remaining)?;
+        parts.push((dots, name));
+        remaining -= value;
+    }
+
+    let mut res = Xml::default();
+    match parts.as_slice() {
+        [] => return None,
+        [(dots, name)] => _ = res.l(format_args!("{dots}{name}")),
+        _ => {
+            res.block("tieDuration").open("list", &[]);
+            for (dots, name) in parts {
+                res.l(format_args!("{dots}{name}"));
+            }
+            res.close().close();
+        }
+    }
+    Some(res.finish())
+}
+// scales ticks by a fermata's hold factor, staying on the 64th note grid
+fn hold_ticks(ticks: u32, factor: f64) -> u32 {
+    let grid = TICKS_PER_WHOLE / 64;
+    (ticks as f64 * factor / grid as f64).round() as u32 * grid
+}
+// notes played at face value that NetsBlox can't express are held for the nearest 64th note instead, unless durations must be exact
+fn face_value_duration(duration: Duration, context: &mut Context) -> Result<Fragment, TranslateError> {
+    match parse_duration(duration) {
+        Err(_) if !context.options.strict_durations => {
+            context.warn(IgnoredElement::Duration(duration));
+            ticks_duration(hold_ticks(duration_ticks(duration), 1.0).max(TICKS_PER_WHOLE / 64)).ok_or(TranslateError::UnsupportedDuration { duration })
+        }
+        x => x,
+    }
+}
+fn shift_octaves(mut note: Note, octaves: i8) -> Note {
+    if !note.is_rest() {
+        note.pitch.octave = note.pitch.octave.saturating_add_signed(octaves);
+    }
+    note
+}
+fn format_note(note: &Note) -> String {
+    format_pitch(note.pitch, note.accidental)
+}
+fn format_pitch(pitch: Pitch, accidental: Accidental) -> String {
+    let accidental = match accidental {
+        Accidental::None => "",
+        Accidental::Natural => "n",
+        Accidental::Sharp => "s",
+        Accidental::DoubleSharp => "ss",
+        Accidental::Flat => "b",
+        Accidental::DoubleFlat => "bb",
+    };
+    format!("{pitch}{accidental}")
+}
+fn grace_note_ticks(grace_notes: &[Vec<Note>], principal_ticks: u32, style: GraceNoteStyle) -> Option<Vec<u32>> {
+    if grace_notes.is_empty() {
+        return Some(vec![]);
+    }
+
+    let acciaccatura = match style {
+        GraceNoteStyle::Notated => grace_notes[0].iter().flat_map(|x| x.iter_modifications()).any(|m| matches!(m.r#type, NoteModificationType::Grace { acciaccatura: true })),
+        GraceNoteStyle::Acciaccatura => true,
+        GraceNoteStyle::Appoggiatura => false,
+    };
+
+    // acciaccaturas steal a thirty-second each, while appoggiaturas split half the principal note between them
+    let count = grace_notes.len() as u32;
+    let share = match acciaccatura {
+        true => (principal_ticks / 2 / count).min(TICKS_PER_WHOLE / 32),
+        false => principal_ticks / 2 / count,
+    };
+    let share = share - share % (TICKS_PER_WHOLE / 64);
+    if share == 0 { None } else { Some(vec![share; grace_notes.len()]) }
+}
+fn write_notes(notes: &[Note], duration: &Fragment, output: &mut Xml) {
+    let notes = notes.iter().filter(|x| !x.is_rest()).collect::<Vec<_>>();
+    match notes.is_empty() {
+        true => output.block("rest").append(duration).close(),
+        false => {
+            output.block("playNotes").append(duration).open("list", &[]);
+            for note in notes {
+                output.l(format_note(note));
+            }
+            output.close().close()
+        }
+    };
 }
-fn translate_chord(raw_notes: &[Note], raw_mods: &[ChordModificationType], output: &mut String, context: &mut Context) -> Result<(), TranslateError> {
+// grace notes keep the phrase's mods, but not the articulations of the principal note
+fn set_grace_note_mods(output: &mut Xml, context: &mut Context) {
+    let mods = context.phrase_mods.iter().copied().collect();
+    context.modifiers.set(&mods, output);
+}
+fn write_grace_notes(grace_notes: &[Vec<Note>], ticks: &[u32], output: &mut Xml, context: &mut Context) {
+    debug_assert_eq!(grace_notes.len(), ticks.len());
+    if !grace_notes.is_empty() {
+        set_grace_note_mods(output, context);
+    }
+    for (notes, ticks) in iter::zip(grace_notes, ticks) {
+        write_notes(notes, &ticks_duration(*ticks).unwrap(), output);
+    }
+}
+fn write_durations(durations: &[Fragment], block: &str, output: &mut Xml) {
+    match durations {
+        [duration] => _ = output.append(duration),
+        _ => {
+            output.block(block).open("list", &[]);
+            for duration in durations {
+                output.append(duration);
+            }
+            output.close().close();
+        }
+    }
+}
+fn flush_tie(output: &mut Xml, context: &mut Context) {
+    if let Some(tie) = context.tie.take() {
+        context.modifiers.set(&tie.mods, output);
+        output.block("playNotes");
+        write_durations(&tie.durations, "tieDuration", output);
+        output.open("list", &[]).append(&tie.notes_xml).close().append(&tie.comment).close().append(&tie.after);
+    }
+}
+fn flush_pending(output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
+    flush_tie(output, context);
+    if context.tempo_restore {
+        sync_tempo(context.position, output, context);
+    }
+
+    // grace notes with no principal note to borrow from are just played at face value
+    for notes in core::mem::take(&mut context.grace_notes) {
+        write_notes(&notes, &face_value_duration(notes[0].duration, context)?, output);
+    }
+    Ok(())
+}
+fn note_mods<'a>(mods: impl Iterator<Item = &'a NoteModificationType> + 'a) -> impl Iterator<Item = Mod> + 'a {
+    mods.flat_map(|m| Some(match m {
+        NoteModificationType::Accent | NoteModificationType::SoftAccent => Mod::Accent,
+        NoteModificationType::Staccato | NoteModificationType::Staccatissimo => Mod::Staccato,
+        NoteModificationType::Turn { upper, delayed: _, vertical: _ } => if *upper { Mod::TurnUpper } else { Mod::TurnLower },
+        _ => return None,
+    }))
+}
+// mods shared by every sounding note of a phrase (none means no constraint, e.g. only rests)
+fn common_mods(phrase: &Phrase, inherited: &BTreeSet<Mod>, visited: &mut BTreeSet<*const Phrase>) -> Option<BTreeSet<Mod>> {
+    if !visited.insert(phrase as *const _) {
+        return None;
+    }
+
+    let mut inherited = inherited.clone();
+    if phrase.iter_modifications().any(|x| x.r#type == PhraseModificationType::Legato) {
+        inherited.insert(Mod::Legato);
+    }
+
+    let chord_mods = |notes: &mut dyn Iterator<Item = &Note>, raw_mods: &[NoteModificationType]| {
+        let notes = notes.filter(|x| !x.is_rest() && !x.is_grace_note()).collect::<Vec<_>>();
+        if notes.is_empty() { return None; }
+        Some(note_mods(notes.iter().flat_map(|n| n.iter_modifications().map(|x| &x.r#type)).chain(raw_mods)).chain(inherited.iter().copied()).collect::<BTreeSet<_>>())
+    };
+    let res = phrase.iter().filter_map(|content| match content {
+        PhraseContent::Note(note) => chord_mods(&mut iter::once(note), &[]),
+        PhraseContent::Chord(chord) => chord_mods(&mut chord.iter().map(|x| match x { ChordContent::Note(note) => note }), &chord.iter_modifications().flat_map(|x| NoteModification::from_chord_modification(&x.r#type)).map(|x| x.r#type).collect::<Vec<_>>()),
+        PhraseContent::Phrase(sub_phrase) => common_mods(sub_phrase, &inherited, visited),
+        PhraseContent::MultiVoice(_) => None,
+    }).reduce(|a, b| &a & &b);
+
+    visited.remove(&(phrase as *const _));
+    res
+}
+fn sync_tempo(position: f64, output: &mut Xml, context: &mut Context) {
+    let tempo = (context.tempo_at(position) * context.tempo_scale * 1000.0).round() / 1000.0;
+    context.tempo_restore = false;
+    if tempo != context.tempo {
+        context.tempo = tempo;
+        output.block("setBPM").l(tempo).close();
+    }
+}
+fn translate_chord(raw_notes: &[Note], raw_mods: &[ChordModificationType], output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
+    for m in raw_mods.iter().filter(|x| NoteModification::from_chord_modification(x).is_none()) {
+        context.warn(IgnoredElement::ChordModification(*m));
+    }
     let raw_mods = raw_mods.iter().flat_map(NoteModification::from_chord_modification).map(|x| x.r#type).collect::<Vec<_>>();
 
+    // octave shifts (8va, 8vb, etc.) and transposition move everything to the sounding pitch
+    let shifted;
+    let raw_notes = match (context.octave_shift, context.transposition) {
+        (0, x) if x == Transposition::default() => raw_notes,
+        _ => {
+            shifted = raw_notes.iter().map(|x| context.sounding_note(x.clone())).collect::<Vec<_>>();
+            &shifted
+        }
+    };
+    let glide = context.glide.take().map(|(x, element)| (context.sounding_note(x), element));
+
     for m in raw_notes.iter().flat_map(|n| n.iter_modifications()).map(|m| &m.r#type).chain(&raw_mods) {
         match m {
-            NoteModificationType::Dynamic { dynamic } => write!(output, r#"<block s="setAudioEffect"><l>Volume</l><l>{}</l></block>"#, 100.0 * dynamic.value()).unwrap(),
-            _ => (),
+            NoteModificationType::Dynamic { dynamic } => {
+                flush_tie(output, context);
+                context.volume = dynamic_volume(dynamic);
+                context.target_volume = context.volume;
+                output.block("setAudioEffect").l("Volume").l(context.volume).close();
+
+                // an explicit dynamic inside a ramp restarts the ramp from here
+                if let Some(ramp) = context.volume_ramp.as_mut() {
+                    ramp.start = context.position;
+                    ramp.from = context.volume;
+                }
+            }
+            NoteModificationType::Accent | NoteModificationType::SoftAccent | NoteModificationType::Staccato | NoteModificationType::Staccatissimo | NoteModificationType::Turn { .. } | NoteModificationType::Grace { .. } | NoteModificationType::Tie | NoteModificationType::Fermata | NoteModificationType::Tremolo { .. } => (),
+            x if Ornament::from_modification(x).is_some() => (),
+            NoteModificationType::Glissando { from_current, .. } | NoteModificationType::Portamento { from_current, .. } if context.options.glissandos != GlissandoStyle::Off => if *from_current && glide.is_none() {
+                context.warn(IgnoredElement::NoteModification(*m));
+            }
+            x => context.warn(IgnoredElement::NoteModification(*x)),
         }
     }
 
-    // in the future, beatblox will support grace notes - but for now, just ignore them
-    let raw_notes = raw_notes.iter().filter(|x| !x.iter_modifications().any(|m| matches!(m.r#type, NoteModificationType::Grace { .. })));
+    // grace notes are held until we reach the principal note they borrow time from
+    let (grace_notes, raw_notes): (Vec<&Note>, Vec<&Note>) = raw_notes.iter().partition(|x| x.is_grace_note());
+    if !grace_notes.is_empty() {
+        flush_tie(output, context);
+        context.grace_notes.push(grace_notes.into_iter().cloned().collect());
+    }
 
-    let (notes, shortest_duration) = match raw_notes.clone().map(|x| x.duration).reduce(|a, b| if a.value() <= b.value() { a } else { b }) {
-        Some(x) => (raw_notes.filter(|x| !x.is_rest()), parse_duration(x)?),
+    let shortest = match raw_notes.iter().map(|x| x.duration).reduce(|a, b| if a.value() <= b.value() { a } else { b }) {
+        Some(x) => x,
         None => return Ok(()),
     };
+    let onset = context.position;
+    let length = shortest.value() * context.tuplet_ratio;
+    let midpoint = onset + length / 2.0;
+    context.position += length;
+    let mut grace_notes = core::mem::take(&mut context.grace_notes);
+    let grace_ticks = match grace_note_ticks(&grace_notes, duration_ticks(shortest), context.options.grace_notes) {
+        Some(x) => x,
+        None => {
+            // principal notes too short to borrow from leave their grace notes at face value
+            set_grace_note_mods(output, context);
+            for notes in core::mem::take(&mut grace_notes) {
+                if let Some(m) = notes.iter().flat_map(|x| x.iter_modifications()).find(|x| matches!(x.r#type, NoteModificationType::Grace { .. })) {
+                    context.warn(IgnoredElement::NoteModification(m.r#type));
+                }
+                write_notes(&notes, &face_value_duration(notes[0].duration, context)?, output);
+            }
+            vec![]
+        }
+    };
+    let borrowed_ticks = grace_ticks.iter().sum::<u32>();
+    let fermata = raw_notes.iter().flat_map(|n| n.iter_modifications()).map(|m| &m.r#type).chain(&raw_mods).any(|x| *x == NoteModificationType::Fermata);
+    let hold_factor = match fermata && context.options.fermata_style == FermataStyle::Extend {
+        true => context.options.fermata_factor,
+        false => 1.0,
+    };
+    let grid_duration = |duration: Duration, scale: f64| duration_ticks(duration).checked_sub(borrowed_ticks).map(|x| hold_ticks(x, hold_factor * scale)).and_then(ticks_duration).ok_or(TranslateError::UnsupportedDuration { duration });
+    let parse_note_duration = |duration: Duration, scale: f64| match (borrowed_ticks, hold_factor * scale == 1.0) {
+        (0, true) => parse_duration(duration),
+        _ => grid_duration(duration, scale),
+    };
+
+    // durations NetsBlox can't express (e.g. 128th notes) are played as a multiple of 64th notes at a scaled tempo
+    let duration_scale = match parse_note_duration(shortest, 1.0) {
+        Ok(_) => 1.0,
+        Err(e) if context.options.strict_durations => return Err(e),
+        Err(e) => match duration_ticks(shortest).checked_sub(borrowed_ticks).map(|x| x as f64 * hold_factor).filter(|x| *x > 0.0) {
+            Some(ticks) => {
+                let grid = (TICKS_PER_WHOLE / 64) as f64;
+                (ticks / grid).floor().max(1.0) * grid / ticks
+            }
+            None => return Err(e),
+        }
+    };
+    // the tempo is shared by every voice, so inside a fork they are just held for the rounded duration
+    let tempo_scale = match context.multivoices.is_empty() {
+        true => duration_scale,
+        false => {
+            if duration_scale != 1.0 {
+                context.warn(IgnoredElement::Duration(shortest));
+            }
+            1.0
+        }
+    };
+    let parse_note_duration = |duration: Duration| match parse_note_duration(duration, duration_scale) {
+        Err(_) if !context.options.strict_durations => grid_duration(duration, duration_scale),
+        x => x,
+    };
+
+    let shortest_duration = parse_note_duration(shortest)?;
+    let outer_tempo_scale = context.tempo_scale;
+
+    // alternatively, the fermata is held by waiting (in seconds at the current tempo) after the note
+    let mut after = Xml::default();
+    if fermata && context.options.fermata_style == FermataStyle::Wait {
+        after.block("doWait").l(((context.options.fermata_factor - 1.0).max(0.0) * length * 240.0 / context.tempo_at(midpoint) * 1000.0).round() / 1000.0).close();
+    }
+    let after = after.finish();
+    let notes = raw_notes.iter().copied().filter(|x| !x.is_rest());
 
     if notes.clone().next().is_some() {
-        let mut notes_xml = String::new();
+        let mut notes_xml = Xml::default();
         let mut durations_xml = vec![];
         for note in notes.clone() {
-            let accidental = match note.accidental {
-                Accidental::None => "",
-                Accidental::Natural => "n",
-                Accidental::Sharp => "s",
-                Accidental::DoubleSharp => "ss",
-                Accidental::Flat => "b",
-                Accidental::DoubleFlat => "bb",
-            };
-            write!(notes_xml, "<l>{pitch}{accidental}</l>", pitch = note.pitch).unwrap();
-            durations_xml.push(parse_duration(note.duration)?);
+            notes_xml.l(format_note(note));
+            durations_xml.push(parse_note_duration(note.duration)?);
         }
         if !durations_xml.contains(&shortest_duration) {
-            write!(notes_xml, "<l>rest</l>").unwrap();
+            notes_xml.l("rest");
             durations_xml.push(shortest_duration);
         }
-        let durations_xml = if durations_xml.iter().all(|x| *x == durations_xml[0]) { durations_xml.into_iter().next().unwrap() } else { format!(r#"<block s="reportNewList"><list>{}</list></block>"#, durations_xml.join("")) };
+        let notes_xml = notes_xml.finish();
+        let uniform_durations = durations_xml.iter().all(|x| *x == durations_xml[0]);
+        let durations_xml = match uniform_durations {
+            true => durations_xml.into_iter().next().unwrap(),
+            false => {
+                let mut res = Xml::default();
+                write_durations(&durations_xml, "reportNewList", &mut res);
+                res.finish()
+            }
+        };
+
+        let mods = note_mods(notes.clone().flat_map(|n| n.iter_modifications().map(|x| &x.r#type)).chain(&raw_mods)).chain(context.phrase_mods.iter().copied()).collect();
+
+        // ornaments, tremolos and glissandos are written out as a sequence of notes filling the (uniform) duration of the chord
+        let mut note_sequence: Option<Vec<(Fragment, u32)>> = None;
+        let ticks = duration_ticks(shortest).checked_sub(borrowed_ticks).map(|x| hold_ticks(x, hold_factor * duration_scale)).filter(|_| uniform_durations);
+        let tremolo = notes.clone().flat_map(|n| n.iter_modifications().map(|x| &x.r#type)).chain(&raw_mods).find_map(|x| match x {
+            NoteModificationType::Tremolo { relative_speed } => Some(*relative_speed),
+            _ => None,
+        });
+        if let Some(ornament) = notes.clone().flat_map(|n| n.iter_modifications().map(|x| &x.r#type)).chain(&raw_mods).find_map(Ornament::from_modification) {
+            match ticks.and_then(|x| ornament.expand(x)) {
+                Some(sequence) => {
+                    let mut res = vec![];
+                    for (steps, ticks) in sequence {
+                        let mut notes_xml = Xml::default();
+                        for note in notes.clone() {
+                            let ornamented = raw_mods.iter().chain(note.iter_modifications().map(|x| &x.r#type)).any(|x| Ornament::from_modification(x).is_some());
+                            let pitch = match (steps, ornamented) {
+                                (0, _) | (_, false) => format_note(note),
+                                _ => diatonic_neighbor(note.pitch, steps, &context.sounding_key()).map(|(pitch, accidental)| format_pitch(pitch, accidental)).unwrap_or_else(|| format_note(note)),
+                            };
+                            notes_xml.l(pitch);
+                        }
+                        res.push((notes_xml.finish(), ticks));
+                    }
+                    note_sequence = Some(res);
+                }
+                None => {
+                    for m in notes.clone().flat_map(|n| n.iter_modifications().map(|x| x.r#type)).chain(raw_mods.iter().copied()).filter(|x| Ornament::from_modification(x).is_some()) {
+                        context.warn(IgnoredElement::NoteModification(m));
+                    }
+                }
+            }
+        } else if let Some(relative_speed) = tremolo {
+            let unit = duration_ticks(Duration::new(tremolo_unit(relative_speed)?, 0));
+            match ticks.filter(|&x| x >= 2 * unit) {
+                Some(ticks) => {
+                    let mut notes_xml = Xml::default();
+                    for note in notes.clone() {
+                        notes_xml.l(format_note(note));
+                    }
+                    let mut sequence = vec![(notes_xml.finish(), unit); (ticks / unit) as usize];
+                    sequence.last_mut().unwrap().1 += ticks % unit;
+                    note_sequence = Some(sequence);
+                }
+                None => context.warn(IgnoredElement::NoteModification(NoteModificationType::Tremolo { relative_speed })),
+            }
+        } else if let Some((target, element)) = &glide {
+            // only the note carrying the glissando slides, while the rest of the chord is struck along with each step
+            let notes = notes.clone().collect::<Vec<_>>();
+            let sliding = notes.iter().position(|n| n.iter_modifications().any(|m| is_glide(&m.r#type))).unwrap_or(0);
+            match ticks.and_then(|x| slide(notes[sliding], target, context.options.glissandos, &context.sounding_key(), x)) {
+                Some(sequence) => note_sequence = Some(sequence.into_iter().map(|((pitch, accidental), ticks)| {
+                    let mut notes_xml = Xml::default();
+                    for (i, note) in notes.iter().enumerate() {
+                        match i == sliding {
+                            true => notes_xml.l(format_pitch(pitch, accidental)),
+                            false => notes_xml.l(format_note(note)),
+                        };
+                    }
+                    (notes_xml.finish(), ticks)
+                }).collect()),
+                None => context.warn(element.clone()),
+            }
+        }
 
-        let mods = notes.flat_map(|n| n.iter_modifications().map(|x| &x.r#type)).chain(&raw_mods).flat_map(|m| Some(match &m {
-            NoteModificationType::Accent | NoteModificationType::SoftAccent => Mod::Accent,
-            NoteModificationType::Staccato | NoteModificationType::Staccatissimo => Mod::Staccato,
-            NoteModificationType::Turn { upper, delayed: _, vertical: _ } => if *upper { Mod::TurnUpper } else { Mod::TurnLower },
-            _ => return None,
-        })).collect();
-        context.modifiers.set(&mods, output);
+        // tied chords are held back so that following chords with the same pitches can be merged into a single note
+        let tieable = uniform_durations && grace_notes.is_empty() && note_sequence.is_none() && duration_scale == 1.0;
+        let tied = raw_mods.contains(&NoteModificationType::Tie) || notes.clone().all(|n| n.iter_modifications().any(|m| m.r#type == NoteModificationType::Tie));
+        let mut pitches = notes.clone().map(format_note).collect::<Vec<_>>();
+        pitches.sort();
 
-        write!(output, r#"<block s="playNotes">{durations_xml}<list>{notes_xml}</list></block>"#).unwrap();
+        if let Some(tie) = context.tie.as_mut().filter(|x| tieable && x.pitches == pitches) {
+            tie.durations.push(durations_xml);
+            tie.after.push(&after);
+            if !tied {
+                flush_tie(output, context);
+            }
+            return Ok(());
+        }
+        flush_tie(output, context);
+        context.tempo_scale *= tempo_scale;
+        sync_tempo(midpoint, output, context);
+
+        let volume = context.volume_ramp.as_ref().map(|x| x.value_at(midpoint)).unwrap_or(context.target_volume);
+        if volume != context.volume {
+            context.volume = volume;
+            output.block("setAudioEffect").l("Volume").l(volume).close();
+        }
+
+        let comment = context.measure_comment(onset);
+        if let Some(sequence) = note_sequence {
+            write_grace_notes(&grace_notes, &grace_ticks, output, context);
+            context.modifiers.set(&mods, output);
+            for (i, (notes_xml, ticks)) in sequence.into_iter().enumerate() {
+                let duration = ticks_duration(ticks).ok_or(TranslateError::UnsupportedDuration { duration: shortest })?;
+                output.block("playNotes").append(&duration).open("list", &[]).append(&notes_xml).close();
+                if i == 0 {
+                    output.append(&comment);
+                }
+                output.close();
+            }
+            output.append(&after);
+        } else if tieable && tied {
+            context.tie = Some(TiedChord { pitches, notes_xml, durations: vec![durations_xml], mods, comment, after });
+        } else {
+            write_grace_notes(&grace_notes, &grace_ticks, output, context);
+            context.modifiers.set(&mods, output);
+            output.block("playNotes").append(&durations_xml).open("list", &[]).append(&notes_xml).close().append(&comment).close().append(&after);
+        }
     } else {
-        write!(output, r#"<block s="rest">{shortest_duration}</block>"#).unwrap();
+        flush_tie(output, context);
+        context.tempo_scale *= tempo_scale;
+        sync_tempo(midpoint, output, context);
+        let comment = context.measure_comment(onset);
+        write_grace_notes(&grace_notes, &grace_ticks, output, context);
+        output.block("rest").append(&shortest_duration).append(&comment).close().append(&after);
     }
 
+    // scaled durations go back to the real tempo with the next unscaled note (or anything else that needs it)
+    if tempo_scale != 1.0 {
+        context.tempo_scale = outer_tempo_scale;
+        context.tempo_restore = true;
+    }
     Ok(())
 }
-fn translate_phrase(phrase: &Phrase, output: &mut String, context: &mut Context) -> Result<(), TranslateError> {
+fn chord_notes(chord: &Chord) -> Vec<&Note> {
+    chord.iter().map(|x| match x { ChordContent::Note(note) => note }).collect()
+}
+// the notes played at the start of a phrase element (looking into nested phrases if requested)
+fn phrase_notes(content: &PhraseContent, nested: bool) -> Vec<&Note> {
+    match content {
+        PhraseContent::Note(note) => vec![note],
+        PhraseContent::Chord(chord) => chord_notes(chord),
+        PhraseContent::Phrase(phrase) if nested => phrase.iter().next().map(|x| phrase_notes(x, true)).unwrap_or_default(),
+        PhraseContent::Phrase(_) | PhraseContent::MultiVoice(_) => vec![],
+    }
+}
+fn staff_notes(content: &StaffContent, nested: bool) -> Vec<&Note> {
+    match content {
+        StaffContent::Note(note) => vec![note],
+        StaffContent::Chord(chord) => chord_notes(chord),
+        StaffContent::Phrase(phrase) if nested => phrase.iter().next().map(|x| phrase_notes(x, true)).unwrap_or_default(),
+        StaffContent::Phrase(_) | StaffContent::MultiVoice(_) | StaffContent::Direction(_) => vec![],
+    }
+}
+fn is_glide(m: &NoteModificationType) -> bool {
+    matches!(m, NoteModificationType::Glissando { from_current: true, .. } | NoteModificationType::Portamento { from_current: true, .. })
+}
+// the note that a chord slides into, if it has a glissando (or is inside a glissando phrase), along with the element to report if it can't slide
+fn glide_target(notes: &[&Note], next: &[&Note], glissando: Option<PhraseModificationType>, context: &Context) -> Option<(Note, IgnoredElement)> {
+    if context.options.glissandos == GlissandoStyle::Off || notes.is_empty() {
+        return None;
+    }
+    let element = notes.iter().flat_map(|n| n.iter_modifications()).map(|m| m.r#type).find(is_glide).map(IgnoredElement::NoteModification).or(glissando.map(IgnoredElement::PhraseModification))?;
+    next.iter().find(|x| !x.is_rest() && !x.is_grace_note()).map(|x| ((*x).clone(), element))
+}
+// the dynamic that the next notes start with, which is where an open-ended crescendo or decrescendo is headed
+fn starting_dynamic(next: &[&Note]) -> Option<Dynamic> {
+    next.iter().flat_map(|n| n.iter_modifications()).find_map(|m| match m.r#type {
+        NoteModificationType::Dynamic { dynamic } => Some(dynamic),
+        _ => None,
+    })
+}
+type ChordNotes = (Vec<Note>, Vec<ChordModificationType>);
+
+fn tremolo_chords(phrase: &Phrase, relative_speed: u8) -> Result<Option<Vec<ChordNotes>>, TranslateError> {
+    let mut chords = vec![];
+    for content in phrase.iter() {
+        match content {
+            PhraseContent::Note(note) => chords.push((vec![note.clone()], vec![])),
+            PhraseContent::Chord(chord) => chords.push((chord.iter().map(|x| match x { ChordContent::Note(note) => note.clone() }).collect::<Vec<_>>(), chord.iter_modifications().map(|x| x.r#type).collect())),
+            PhraseContent::Phrase(_) | PhraseContent::MultiVoice(_) => return Ok(None),
+        }
+    }
+    let shortest = |notes: &[Note]| notes.iter().map(|x| duration_ticks(x.duration)).min().unwrap_or(0);
+    if chords.len() < 2 || chords.iter().any(|(notes, _)| notes.iter().any(|x| x.is_grace_note())) {
+        return Ok(None);
+    }
+
+    let unit_type = tremolo_unit(relative_speed)?;
+    let unit = duration_ticks(Duration::new(unit_type, 0));
+    let total = chords.iter().map(|(notes, _)| shortest(notes)).sum::<u32>();
+    if total / unit < chords.len() as u32 {
+        return Ok(Some(chords)); // too slow to alternate, so just play as written
+    }
+
+    // the first pass keeps the written notes (and their modifications), after which only the pitches are repeated
+    let mut res = vec![];
+    for i in 0..(total / unit) as usize {
+        let (notes, mods) = &chords[i % chords.len()];
+        res.push(match i < chords.len() {
+            true => (notes.iter().cloned().map(|mut x| { x.duration = Duration::new(unit_type, 0); x }).collect::<Vec<_>>(), mods.clone()),
+            false => (notes.iter().map(|x| Note::new(x.pitch, Duration::new(unit_type, 0), Some(x.accidental))).collect(), vec![]),
+        });
+    }
+
+    // any leftover time is tied onto the last note in ever smaller subdivisions
+    let mut remainder = total % unit;
+    let mut duration_type = unit_type;
+    while remainder != 0 {
+        duration_type = half_duration_type(duration_type).ok_or(TranslateError::UnsupportedTremolo { relative_speed })?;
+        let ticks = duration_ticks(Duration::new(duration_type, 0));
+        if remainder >= ticks {
+            remainder -= ticks;
+            let (last, _) = res.last_mut().unwrap();
+            for note in last.iter_mut() {
+                note.add_modification(NoteModificationType::Tie);
+            }
+            let notes = last.iter().map(|x| Note::new(x.pitch, Duration::new(duration_type, 0), Some(x.accidental))).collect();
+            res.push((notes, vec![]));
+        }
+    }
+
+    Ok(Some(res))
+}
+fn translate_phrase(phrase: &Phrase, output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
     if !context.phrases.insert(phrase as *const _) {
         return Err(TranslateError::CyclicStructure);
     }
 
+    let phrase_mods_point = context.phrase_mods.len();
     let mut tuplet_mod = None;
+    let mut tuplet_ratio = 1.0;
+    let mut tempo_scale = 1.0;
+    let mut volume_ramp = None;
+    let mut tremolo = None;
+    let mut glissando = None;
+    let (position, outer_tuplet_ratio, outer_octave_shift, volume, next_dynamic) = (context.position, context.tuplet_ratio, context.octave_shift, context.target_volume, context.next_dynamic);
+    let ramp = |to: Option<Dynamic>, step: f64, hairpin: bool| Ramp {
+        start: position,
+        end: position + phrase.get_beats(&Duration::new(DurationType::Whole, 0), Some(outer_tuplet_ratio)),
+        from: volume,
+        to: to.as_ref().map(dynamic_volume).unwrap_or((volume + step).clamp(5.0, 100.0)),
+        hairpin,
+    };
+    // without a final dynamic, gradual changes head for the next one (if it goes the same way)
+    let heading = |step: f64| next_dynamic.filter(|x| (dynamic_volume(x) - volume) * step > 0.0);
     for modification in phrase.iter_modifications() {
         match modification.r#type {
-            PhraseModificationType::Tuplet { num_beats, into_beats } => match (num_beats, into_beats) {
-                (3, 2) => tuplet_mod = Some("Tuplet 3:2"),
-                (5, 4) => tuplet_mod = Some("Tuplet 5:4"),
-                (6, 4) => tuplet_mod = Some("Tuplet 6:4"),
-                (7, 4) => tuplet_mod = Some("Tuplet 7:4"),
-                _ => return Err(TranslateError::UnsupportedTuplet { num_beats, into_beats }),
+            PhraseModificationType::Tuplet { num_beats, into_beats } => {
+                match (num_beats, into_beats) {
+                    (3, 2) => tuplet_mod = Some("Tuplet 3:2"),
+                    (5, 4) => tuplet_mod = Some("Tuplet 5:4"),
+                    (6, 4) => tuplet_mod = Some("Tuplet 6:4"),
+                    (7, 4) => tuplet_mod = Some("Tuplet 7:4"),
+                    (0, _) | (_, 0) => return Err(TranslateError::UnsupportedTuplet { num_beats, into_beats }),
+                    // the tempo is shared by every voice, so other ratios can't be played inside a fork
+                    _ if !context.multivoices.is_empty() => {
+                        context.warn(IgnoredElement::PhraseModification(modification.r#type));
+                        continue;
+                    }
+                    // other ratios are played as written at a scaled tempo
+                    _ => tempo_scale = f64::from(num_beats) / f64::from(into_beats),
+                }
+                tuplet_ratio = f64::from(into_beats) / f64::from(num_beats);
             }
-            _ => (),
+            PhraseModificationType::Legato => context.phrase_mods.push(Mod::Legato),
+            PhraseModificationType::OctaveShift { num_octaves } => context.octave_shift = context.octave_shift.saturating_add(num_octaves),
+            PhraseModificationType::Tremolo { relative_speed } => tremolo = Some(relative_speed),
+            x @ (PhraseModificationType::Glissando | PhraseModificationType::Portamento) if context.options.glissandos != GlissandoStyle::Off => glissando = Some(x),
+            PhraseModificationType::Crescendo { final_dynamic } => volume_ramp = Some(ramp(final_dynamic.or_else(|| heading(DYNAMIC_STEP)), DYNAMIC_STEP, false)),
+            PhraseModificationType::Decrescendo { final_dynamic } => volume_ramp = Some(ramp(final_dynamic.or_else(|| heading(-DYNAMIC_STEP)), -DYNAMIC_STEP, false)),
+            PhraseModificationType::Hairpin { maximum_dynamic } => volume_ramp = Some(ramp(maximum_dynamic, DYNAMIC_STEP, true)),
+            x => context.warn(IgnoredElement::PhraseModification(x)),
         }
     }
 
+    if tuplet_mod.is_some() {
+        // outer mods can only stay open around the tuplet if every note inside uses them
+        flush_pending(output, context)?;
+        if let Some(common) = common_mods(phrase, &context.phrase_mods.iter().copied().collect(), &mut BTreeSet::new()) {
+            let keep = &context.modifiers.active & &common;
+            context.modifiers.set(&keep, output);
+        }
+    }
     let unwind_point = context.modifiers.unwind_point();
+    let tuplet_depth = output.depth();
     if let Some(tuplet_mod) = tuplet_mod {
-        write!(output, r#"<block s="noteMod"><list><l><option>{tuplet_mod}</option></l></list><script>"#).unwrap();
+        output.block("noteMod").open("list", &[]).open("l", &[]).leaf("option", &[], tuplet_mod).close().close().open("script", &[]);
     }
 
-    for content in phrase.iter() {
+    context.tuplet_ratio *= tuplet_ratio;
+    let outer_tempo_scale = context.tempo_scale;
+    if tempo_scale != 1.0 {
+        flush_pending(output, context)?;
+        context.tempo_scale *= tempo_scale;
+        sync_tempo(position, output, context);
+    }
+    let final_volume = volume_ramp.as_ref().map(Ramp::final_value);
+    let outer_volume_ramp = volume_ramp.map(|x| context.volume_ramp.replace(x));
+
+    // a (two-note) tremolo alternates between the notes of the phrase for their total duration
+    let tremolo_chords = match tremolo {
+        Some(relative_speed) => match tremolo_chords(phrase, relative_speed)? {
+            Some(x) => Some(x),
+            None => {
+                context.warn(IgnoredElement::PhraseModification(PhraseModificationType::Tremolo { relative_speed }));
+                None
+            }
+        }
+        None => None,
+    };
+    for (notes, mods) in tremolo_chords.iter().flatten() {
+        translate_chord(notes, mods, output, context)?;
+    }
+
+    let contents = phrase.iter().filter(|_| tremolo_chords.is_none()).collect::<Vec<_>>();
+    for (i, content) in contents.iter().copied().enumerate() {
+        // notes that slide into the next one need to know where they're going
+        let next = contents.get(i + 1).map(|x| phrase_notes(x, true)).unwrap_or_default();
+        context.glide = glide_target(&phrase_notes(content, false), &next, glissando, context);
+        context.next_dynamic = match i + 1 == contents.len() {
+            true => next_dynamic,
+            false => starting_dynamic(&next),
+        };
         match content {
-            PhraseContent::Note(note) => translate_chord(&[note.clone()], &[], output, context)?,
+            PhraseContent::Note(note) => translate_chord(slice::from_ref(note), &[], output, context)?,
             PhraseContent::Chord(chord) => translate_chord(&chord.iter().map(|x| match x { ChordContent::Note(note) => note.clone() }).collect::<Vec<_>>(), &chord.iter_modifications().map(|x| x.r#type).collect::<Vec<_>>(), output, context)?,
             PhraseContent::Phrase(sub_phrase) => translate_phrase(sub_phrase, output, context)?,
-            PhraseContent::MultiVoice(_) => (),
+            PhraseContent::MultiVoice(multivoice) => translate_multivoice(multivoice, output, context)?,
         }
     }
 
     if tuplet_mod.is_some() {
-        write!(output, r#"</script></block>"#).unwrap();
+        flush_pending(output, context)?;
         context.modifiers.unwind_to(unwind_point, output);
+        output.close_to(tuplet_depth);
+    }
+    if tempo_scale != 1.0 {
+        context.tempo_scale = outer_tempo_scale;
+        flush_pending(output, context)?;
+        sync_tempo(context.position, output, context);
+    }
+    context.phrase_mods.truncate(phrase_mods_point);
+    context.tuplet_ratio = outer_tuplet_ratio;
+    context.next_dynamic = next_dynamic;
+    context.octave_shift = outer_octave_shift;
+
+    // the ramp's final volume is applied lazily by the next sounding note
+    if let (Some(outer_volume_ramp), Some(final_volume)) = (outer_volume_ramp, final_volume) {
+        context.volume_ramp = outer_volume_ramp;
+        context.target_volume = final_volume;
     }
 
     assert!(context.phrases.remove(&(phrase as *const _)));
     Ok(())
 }
-fn translate_staff(staff: &Staff, output: &mut String, context: &mut Context) -> Result<(), TranslateError> {
+// repeated phrases (that don't share held back notes with their surroundings) can be played by a shared block
+fn translate_phrase_block(phrase: &Phrase, output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
+    let standalone = |context: &Context| context.tie.is_none() && context.grace_notes.is_empty() && context.modifiers.active.is_empty();
+    if !context.options.phrase_blocks || !standalone(context) {
+        return translate_phrase(phrase, output, context);
+    }
+
+    // every pass closes the phrase's note mods at the end, block or not, so that they all play out the same
+    let block = match context.pass {
+        Pass::Count => {
+            let mut digest = Digest::default();
+            let mut body = Xml::new(&mut digest, false);
+            translate_phrase(phrase, &mut body, context)?;
+            context.modifiers.set(&Default::default(), &mut body);
+            drop(body);
+            let hash = standalone(context).then(|| digest.value());
+            if let Some(hash) = hash {
+                *context.blocks.phrase_counts.entry(hash).or_default() += 1;
+            }
+            context.blocks.phrase_hashes.push_back(hash);
+            return Ok(());
+        }
+        Pass::Sprites => {
+            let hash = context.blocks.phrase_hashes.pop_front().unwrap().filter(|x| context.blocks.phrase_counts[x] > 1);
+            let block = hash.map(|x| context.blocks.block(x, "phrase"));
+            context.blocks.phrases.push_back(block.clone());
+            block
+        }
+        Pass::Blocks => context.blocks.phrases.pop_front().unwrap(),
+    };
+
+    match block {
+        Some(block) => {
+            output.empty("custom-block", &[("s", &block.0)]);
+            let mut sink = context.block_output(&block);
+            let mut discard = Xml::discard();
+            let body = sink.as_mut().unwrap_or(&mut discard);
+            body.open("block-definition", &[("s", &block.0), ("type", &"command"), ("category", &"music")]).empty("inputs", &[]).open("script", &[]);
+            translate_phrase(phrase, body, context)?;
+            context.modifiers.set(&Default::default(), body);
+            body.close().close();
+            context.sink = context.sink.take().or(sink);
+        }
+        None => {
+            translate_phrase(phrase, output, context)?;
+            context.modifiers.set(&Default::default(), output);
+        }
+    }
+    Ok(())
+}
+fn translate_multivoice(multivoice: &MultiVoice, output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
+    if !context.multivoices.insert(multivoice as *const _) {
+        return Err(TranslateError::CyclicStructure);
+    }
+
+    // each voice is launched as its own thread, and the voice counter (unique per nesting depth) lets us rejoin once they all finish
+    let voices = multivoice.num_items();
+    if voices != 0 {
+        flush_pending(output, context)?;
+        let counter = match context.multivoices.len() {
+            1 => String::from("voices"),
+            x => format!("voices {x}"),
+        };
+        output.block("doDeclareVariables").open("list", &[]).l(&counter).close().close();
+        output.block("doSetVar").l(&counter).l(voices).close();
+
+        let (start, start_measures) = (context.position, context.measures.clone());
+        let (mut end, mut end_measures) = (start, start_measures.clone());
+        for content in multivoice.iter() {
+            // note mods do not carry over into launched scripts, so each voice manages its own
+            let outer_modifiers = core::mem::take(&mut context.modifiers);
+            context.position = start;
+            context.measures = start_measures.clone();
+            output.block("fork").block("reifyScript").open("script", &[]);
+            match content {
+                MultiVoiceContent::Phrase(phrase) => translate_phrase(phrase, output, context)?,
+            }
+            flush_pending(output, context)?;
+            context.modifiers.set(&Default::default(), output);
+            output.block("doChangeVar").l(&counter).l(-1).close();
+            output.close().empty("list", &[]).close().empty("list", &[]).close();
+            context.modifiers = outer_modifiers;
+            if context.position >= end {
+                end = context.position;
+                end_measures = context.measures.clone();
+            }
+        }
+        context.position = end;
+        context.measures = end_measures;
+
+        output.block("doWaitUntil").block("reportVariadicEquals").open("list", &[]).empty("block", &[("var", &counter)]).l(0).close().close().close();
+    }
+
+    assert!(context.multivoices.remove(&(multivoice as *const _)));
+    Ok(())
+}
+fn translate_staff(staff: &Staff, output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
     if !context.staffs.insert(staff as *const _) {
         return Err(TranslateError::CyclicStructure);
     }
 
-    for content in staff.iter() {
+    context.path.push(staff.get_name().into());
+    let contents = staff.iter().collect::<Vec<_>>();
+    for (i, content) in contents.iter().copied().enumerate() {
+        let next = contents[i + 1..].iter().find(|x| !matches!(x, StaffContent::Direction(_)));
+        let next = next.map(|x| staff_notes(x, true)).unwrap_or_default();
+        context.glide = glide_target(&staff_notes(content, false), &next, None, context);
+        context.next_dynamic = starting_dynamic(&next);
         match content {
-            StaffContent::Note(note) => translate_chord(&[note.clone()], &[], output, context)?,
+            StaffContent::Note(note) => translate_chord(slice::from_ref(note), &[], output, context)?,
             StaffContent::Chord(chord) => translate_chord(&chord.iter().map(|x| match x { ChordContent::Note(note) => note.clone() }).collect::<Vec<_>>(), &chord.iter_modifications().map(|x| x.r#type).collect::<Vec<_>>(), output, context)?,
-            StaffContent::Phrase(phrase) => translate_phrase(phrase, output, context)?,
+            StaffContent::Phrase(phrase) => translate_phrase_block(phrase, output, context)?,
             StaffContent::Direction(direction) => match &direction.r#type {
-                DirectionType::KeyChange { key } => write!(output, r#"<block s="setKey"><l>{key_sig:?}{key_mode:?}</l></block>"#, key_sig = key.signature, key_mode = key.mode).unwrap(),
-                _ => (),
+                DirectionType::KeyChange { key } => {
+                    flush_pending(output, context)?;
+                    context.key = *key;
+                    let key = context.sounding_key();
+                    output.block("setKey").l(format_args!("{:?}{:?}", key.signature, key.mode)).close();
+                }
+                DirectionType::TimeSignatureChange { time_signature } => {
+                    // changes take effect from the measure containing the current position
+                    context.measures.advance_to(context.position);
+                    context.measures.length = measure_length(time_signature);
+                    if *time_signature != context.time_signature {
+                        flush_pending(output, context)?;
+                        context.time_signature = *time_signature;
+                        if time_signature.signature != TimeSignatureType::None {
+                            output.block("setTimeSignature").l(time_signature.numerator).l(time_signature.denominator).close();
+                        }
+                    }
+                }
+                x => context.warn(IgnoredElement::Direction(*x)),
             }
-            StaffContent::MultiVoice(_) => (),
+            StaffContent::MultiVoice(multivoice) => translate_multivoice(multivoice, output, context)?,
         }
     }
 
+    context.path.pop();
     assert!(context.staffs.remove(&(staff as *const _)));
     Ok(())
 }
-fn translate_section(section: &Section, output: &mut String, context: &mut Context) -> Result<(), TranslateError> {
+fn section_length(section: &Section) -> f64 {
+    section.iter().map(|content| match content {
+        SectionContent::Staff(staff) => staff.get_beats(&Duration::new(DurationType::Whole, 0)),
+        SectionContent::Section(section) => section_length(section),
+    }).sum()
+}
+// whether any nested section (belonging to this repeat rather than a nested one) only plays on certain iterations
+fn has_endings(section: &Section) -> bool {
+    section.iter().any(|content| match content {
+        SectionContent::Staff(_) => false,
+        SectionContent::Section(sub_section) => !sub_section.get_playable_iterations().is_empty() || (sub_section.get_total_iterations() == 1 && has_endings(sub_section)),
+    })
+}
+fn section_tempo(section: &Section) -> Option<f64> {
+    section.get_section_tempo().map(|x| quarter_note_tempo(&x))
+}
+fn set_tempo(tempo: f64, output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
+    context.tempo_restore = false; // replaced right away anyway
+    flush_pending(output, context)?;
+    context.tempo = tempo;
+    context.target_tempo = tempo;
+    output.block("setBPM").l(tempo).close();
+    Ok(())
+}
+fn translate_section(section: &Section, next_tempo: Option<f64>, output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
     if !context.sections.insert(section as *const _) {
         return Err(TranslateError::CyclicStructure);
     }
 
+    context.path.push(section.get_name().into());
+
     let mut repetitions = 1;
+    let mut tempo = None;
+    let mut tempo_change = None;
+    let mut only_play = None;
     for modification in section.iter_modifications() {
         match &modification.r#type {
             SectionModificationType::Repeat { num_times } => repetitions += *num_times as usize,
-            SectionModificationType::TempoExplicit { tempo } => write!(output, r#"<block s="setBPM"><l>{tempo}</l></block>"#, tempo = quarter_note_tempo(tempo)).unwrap(),
-            SectionModificationType::TempoImplicit { tempo } => write!(output, r#"<block s="setBPM"><l>{tempo}</l></block>"#, tempo = tempo.value()).unwrap(),
-            _ => (),
+            SectionModificationType::TempoExplicit { tempo: x } => tempo = Some(quarter_note_tempo(x)),
+            SectionModificationType::TempoImplicit { tempo: x } => tempo = Some(x.value().into()),
+            SectionModificationType::Accelerando | SectionModificationType::Stringendo => tempo_change = Some((TEMPO_RATIO, true)),
+            SectionModificationType::Rallentando | SectionModificationType::Ritardando => tempo_change = Some((1.0 / TEMPO_RATIO, true)),
+            SectionModificationType::Ritenuto => tempo_change = Some((1.0 / TEMPO_RATIO, false)),
+            SectionModificationType::OnlyPlay { iterations } => only_play = Some(iterations),
+        }
+    }
+
+    // a tempo change starting right away is set by the first note, so an explicit tempo only needs to be its starting point
+    match (tempo, tempo_change) {
+        (Some(tempo), Some(_)) => context.target_tempo = tempo,
+        (Some(tempo), None) => set_tempo(tempo, output, context)?,
+        (None, _) => (),
+    }
+
+    // gradual changes ramp toward the next explicit tempo, whereas ritenuto slows down immediately and recovers after the section
+    let outer_target_tempo = context.target_tempo;
+    let tempo_ramp = match tempo_change {
+        Some((ratio, true)) => Some(Ramp {
+            start: context.position,
+            end: context.position + section_length(section),
+            from: context.target_tempo,
+            to: next_tempo.unwrap_or((context.target_tempo * ratio).round()),
+            hairpin: false,
+        }),
+        Some((ratio, false)) => {
+            context.target_tempo = (context.target_tempo * ratio).round();
+            None
         }
+        None => None,
+    };
+    let final_tempo = tempo_ramp.as_ref().map(Ramp::final_value);
+    let outer_tempo_ramp = tempo_ramp.map(|x| context.tempo_ramp.replace(x));
+
+    // endings are guarded by the iteration counter of the innermost enclosing repeat (outside of any repeat they always play)
+    let guard = match (only_play, context.iterations.last()) {
+        (Some(iterations), Some(counter)) if !iterations.is_empty() => Some((iterations, counter.clone())),
+        (Some(iterations), _) => {
+            context.warn(IgnoredElement::SectionModification(SectionModificationType::OnlyPlay { iterations: iterations.clone() }));
+            None
+        }
+        (None, _) => None,
+    };
+    let guard_depth = output.depth();
+    if let Some((iterations, counter)) = &guard {
+        flush_pending(output, context)?;
+        context.modifiers.set(&Default::default(), output);
+        output.block("doIf").block("reportListContainsItem").block("reportNewList").open("list", &[]);
+        for x in iterations.iter() {
+            output.l(x);
+        }
+        output.close().close().empty("block", &[("var", counter)]).close().open("script", &[]);
     }
 
+    let counter = (repetitions != 1 && has_endings(section)).then(|| match context.iterations.len() + 1 {
+        1 => String::from("iteration"),
+        x => format!("iteration {x}"),
+    });
+    let unwind_point = context.modifiers.unwind_point();
+    let repeat_depth = output.depth();
     if repetitions != 1 {
-        write!(output, r#"<block s="doRepeat"><l>{repetitions}</l><script>"#).unwrap();
+        flush_pending(output, context)?;
+        if let Some(counter) = &counter {
+            output.block("doDeclareVariables").open("list", &[]).l(counter).close().close();
+            output.block("doSetVar").l(counter).l(0).close();
+        }
+        output.block("doRepeat").l(repetitions).open("script", &[]);
+        if let Some(counter) = &counter {
+            output.block("doChangeVar").l(counter).l(1).close();
+        }
+    }
+    if let Some(counter) = counter.clone() {
+        context.iterations.push(counter);
     }
 
-    for content in section.iter() {
+    for (i, content) in section.iter().enumerate() {
         match content {
             SectionContent::Staff(staff) => translate_staff(staff, output, context)?,
-            SectionContent::Section(section) => translate_section(section, output, context)?,
+            SectionContent::Section(sub_section) => {
+                let next_tempo = section.iter().skip(i + 1).find_map(|x| match x { SectionContent::Section(x) => Some(section_tempo(x)), _ => None }).unwrap_or(next_tempo);
+                translate_section(sub_section, next_tempo, output, context)?;
+            }
         }
     }
 
+    if counter.is_some() {
+        context.iterations.pop();
+    }
     if repetitions != 1 {
-        write!(output, r#"</script></block>"#).unwrap();
+        flush_pending(output, context)?;
+        context.modifiers.unwind_to(unwind_point, output);
+        output.close_to(repeat_depth);
+    }
+    if guard.is_some() {
+        flush_pending(output, context)?;
+        context.modifiers.set(&Default::default(), output);
+        output.close_to(guard_depth);
     }
 
+    // like volume ramps, the resulting tempo is applied lazily by the next note or rest
+    if let (Some(outer_tempo_ramp), Some(final_tempo)) = (outer_tempo_ramp, final_tempo) {
+        context.tempo_ramp = outer_tempo_ramp;
+        context.target_tempo = final_tempo;
+    } else if let Some((_, false)) = tempo_change {
+        context.target_tempo = outer_target_tempo;
+    }
+
+    context.path.pop();
     assert!(context.sections.remove(&(section as *const _)));
     Ok(())
 }
-fn translate_part(part: &Part, output: &mut String, context: &mut Context) -> Result<(), TranslateError> {
-    let name = xml_escape(part.get_name());
-    let instrument = match part.get_name().to_lowercase().as_str() {
-        x if x.contains("synth") => "Synthesizer",
-        x if x.contains("bassoon") => "Bassoon",
-        x if x.contains("bass") => "Electric Bass",
-        x if x.contains("cello") => "Cello",
-        x if x.contains("guitar") => match x {
-            x if x.contains("elec") => "Electric Guitar",
-            x if x.contains("nylon") => "Nylon Guitar",
-            _ => "Acoustic Guitar",
-        }
-        x if x.contains("harp") => "Harp",
-        x if x.contains("organ") => "Pipe Organ",
-        x if x.contains("violin") => "Violin",
-        _ => "Grand Piano",
-    };
+// the first entry whose keywords all appear in the name wins, so more specific names must come before the generic ones they contain
+const INSTRUMENT_KEYWORDS: &[(&[&str], &str)] = &[
+    (&["synth"], "Synthesizer"),
+    (&["drum"], "Drum Kit"),
+    (&["percussion"], "Drum Kit"),
+    (&["piccolo"], "Piccolo"),
+    (&["flute"], "Flute"),
+    (&["english horn"], "English Horn"),
+    (&["cor anglais"], "English Horn"),
+    (&["oboe"], "Oboe"),
+    (&["bass clarinet"], "Bass Clarinet"),
+    (&["clarinet"], "Clarinet"),
+    (&["contrabassoon"], "Bassoon"),
+    (&["bassoon"], "Bassoon"),
+    (&["soprano sax"], "Soprano Saxophone"),
+    (&["alto sax"], "Alto Saxophone"),
+    (&["tenor sax"], "Tenor Saxophone"),
+    (&["baritone sax"], "Baritone Saxophone"),
+    (&["sax"], "Alto Saxophone"),
+    (&["flugelhorn"], "Trumpet"),
+    (&["horn"], "French Horn"),
+    (&["trumpet"], "Trumpet"),
+    (&["cornet"], "Trumpet"),
+    (&["trombone"], "Trombone"),
+    (&["tuba"], "Tuba"),
+    (&["euphonium"], "Tuba"),
+    (&["bass guitar"], "Electric Bass"),
+    (&["guitar", "elec"], "Electric Guitar"),
+    (&["guitar", "nylon"], "Nylon Guitar"),
+    (&["guitar", "classical"], "Nylon Guitar"),
+    (&["guitar"], "Acoustic Guitar"),
+    (&["contrabass"], "Contrabass"),
+    (&["double bass"], "Contrabass"),
+    (&["string bass"], "Contrabass"),
+    (&["upright bass"], "Contrabass"),
+    (&["bass"], "Electric Bass"),
+    (&["cello"], "Cello"),
+    (&["viola"], "Viola"),
+    (&["violin"], "Violin"),
+    (&["fiddle"], "Violin"),
+    (&["harp"], "Harp"),
+    (&["organ"], "Pipe Organ"),
+    (&["marimba"], "Marimba"),
+    (&["xylophone"], "Xylophone"),
+    (&["vibraphone"], "Vibraphone"),
+    (&["glockenspiel"], "Xylophone"),
+    (&["piano"], "Grand Piano"),
+    (&["keyboard"], "Grand Piano"),
+];
 
-    write!(output, r#"<sprite name="{name}" x="0" y="0" heading="90" scale="1" volume="100" pan="0" rotation="1" draggable="true" costume="0" color="80,80,80,1" pen="tip"><costumes><list struct="atomic"></list></costumes><sounds><list struct="atomic"></list></sounds><blocks></blocks><variables></variables><scripts>"#).unwrap();
+fn midi_program_instrument(program: u8) -> &'static str {
+    match program {
+        0..=7 => "Grand Piano",
+        11 => "Vibraphone",
+        12 => "Marimba",
+        8..=15 => "Xylophone",
+        16..=23 => "Pipe Organ",
+        24 => "Nylon Guitar",
+        25 => "Acoustic Guitar",
+        26..=31 => "Electric Guitar",
+        32 => "Contrabass",
+        33..=39 => "Electric Bass",
+        41 => "Viola",
+        42 => "Cello",
+        43 => "Contrabass",
+        46 => "Harp",
+        47 => "Drum Kit",
+        40..=51 => "Violin",
+        56 | 59 | 61 => "Trumpet",
+        57 => "Trombone",
+        58 => "Tuba",
+        60 => "French Horn",
+        64 => "Soprano Saxophone",
+        65 => "Alto Saxophone",
+        66 => "Tenor Saxophone",
+        67 => "Baritone Saxophone",
+        68 | 109 | 111 => "Oboe",
+        69 => "English Horn",
+        70 => "Bassoon",
+        71 => "Clarinet",
+        72 => "Piccolo",
+        73..=79 => "Flute",
+        104..=107 => "Acoustic Guitar",
+        108 => "Marimba",
+        110 => "Violin",
+        112..=119 => "Drum Kit",
+        _ => "Synthesizer",
+    }
+}
+fn resolve_instrument(options: &TranslateOptions, source_name: &str, part_name: &str) -> String {
+    if let Some(x) = options.part_instruments.get(source_name).or_else(|| options.part_instruments.get(part_name)) {
+        return x.clone();
+    }
+    if let Some(x) = &options.instrument {
+        return x.clone();
+    }
+    if let Some(x) = options.part_programs.get(source_name).or_else(|| options.part_programs.get(part_name)) {
+        return midi_program_instrument(*x).into();
+    }
+
+    let name = source_name.to_lowercase();
+    if let Some((_, x)) = options.instrument_keywords.iter().find(|(keyword, _)| name.contains(&keyword.to_lowercase())) {
+        return x.clone();
+    }
+    match INSTRUMENT_KEYWORDS.iter().find(|(keywords, _)| keywords.iter().all(|x| name.contains(x))) {
+        Some((_, x)) => (*x).into(),
+        None => "Grand Piano".into(),
+    }
+}
+fn sprite_position(layout: SpriteLayout, index: usize, count: usize, options: &TranslateOptions) -> (f64, f64) {
+    let offset = (index as f64 + 0.5) / count as f64;
+    match layout {
+        SpriteLayout::Stacked => (0.0, 0.0),
+        SpriteLayout::Row => (options.stage_width as f64 * (offset - 0.5), 0.0),
+        SpriteLayout::Column => (0.0, options.stage_height as f64 * (0.5 - offset)),
+    }
+}
+fn translate_part(part: &Part, source_name: &str, position: (f64, f64), output: &mut Xml, context: &mut Context) -> Result<(), TranslateError> {
+    let instrument = resolve_instrument(context.options, source_name, part.get_name());
+    let (x, y) = position;
+    let part_transposition = context.options.part_transpositions.get(source_name).or_else(|| context.options.part_transpositions.get(part.get_name())).copied().unwrap_or_default();
+    context.transposition = context.options.transposition.then(part_transposition);
+    let key = context.transposition.key(&context.starting_key);
 
-    write!(output, r#"<script x="0" y="0"><block s="receiveGo"></block>"#).unwrap();
-    write!(output, r#"<block s="setInstrument"><l>{instrument}</l></block>"#).unwrap();
-    write!(output, r#"<block s="setBPM"><l>{tempo}</l></block>"#, tempo = quarter_note_tempo(&context.starting_tempo)).unwrap();
-    write!(output, r#"<block s="setKey"><l>{key_sig:?}{key_mode:?}</l></block>"#, key_sig = context.starting_key.signature, key_mode = context.starting_key.mode).unwrap();
+    output.open("sprite", &[("name", &part.get_name()), ("x", &x), ("y", &y), ("heading", &90), ("scale", &1), ("volume", &100), ("pan", &0), ("rotation", &1), ("draggable", &true), ("costume", &0), ("color", &"80,80,80,1"), ("pen", &"tip")]);
+    output.open("costumes", &[]).empty("list", &[("struct", &"atomic")]).close();
+    output.open("sounds", &[]).empty("list", &[("struct", &"atomic")]).close();
+    output.empty("blocks", &[]).empty("variables", &[]).open("scripts", &[]);
 
-    for content in part.iter() {
+    output.open("script", &[("x", &0), ("y", &0)]).block("receiveGo").close();
+    output.block("setInstrument").l(instrument).close();
+    output.block("setBPM").l(quarter_note_tempo(&context.starting_tempo)).close();
+    output.block("setKey").l(format_args!("{:?}{:?}", key.signature, key.mode)).close();
+
+    context.position = 0.0;
+    context.key = context.starting_key;
+    context.time_signature = context.starting_time_signature;
+    context.measures = Measures::new(&context.starting_time_signature);
+    context.volume = 100.0;
+    context.target_volume = 100.0;
+    context.tempo = quarter_note_tempo(&context.starting_tempo);
+    context.target_tempo = context.tempo;
+
+    context.path.push(part.get_name().into());
+    for (i, content) in part.iter().enumerate() {
+        let next_tempo = part.iter().skip(i + 1).map(|x| match x { PartContent::Section(x) => section_tempo(x) }).next().flatten();
         debug_assert!(context.modifiers.stack.is_empty() && context.modifiers.active.is_empty());
         match content {
-            PartContent::Section(section) => {
-                let block_name = iter::once(String::new()).chain((2usize..).map(|x| format!(" {x}"))).map(|x| format!("{}{x}", section.get_name())).find(|x| !context.blocks.contains_key(x)).unwrap();
-                let mut block_def = format!(r#"<block-definition s={block_name:?} type="command" category="music"><inputs></inputs><script>"#);
-                translate_section(section, &mut block_def, context)?;
-                context.modifiers.set(&Default::default(), &mut block_def);
-                write!(block_def, "</script></block-definition>").unwrap();
-                write!(output, r#"<custom-block s={block_name:?}></custom-block>"#).unwrap();
-                context.blocks.insert(block_name, block_def);
+            PartContent::Section(section) => match context.options.section_blocks {
+                true => {
+                    // sections with identical bodies share a single block
+                    let mut digest = Digest::default();
+                    let block = match context.pass {
+                        Pass::Count => (String::new(), false),
+                        Pass::Sprites => {
+                            let mut body = Xml::new(&mut digest, false);
+                            translate_section(section, next_tempo, &mut body, context)?;
+                            flush_pending(&mut body, context)?;
+                            context.modifiers.set(&Default::default(), &mut body);
+                            drop(body);
+                            let block = context.blocks.block(digest.value(), section.get_name());
+                            context.blocks.sections.push_back(block.clone());
+                            block
+                        }
+                        Pass::Blocks => context.blocks.sections.pop_front().unwrap(),
+                    };
+                    if context.pass != Pass::Sprites {
+                        let mut sink = context.block_output(&block);
+                        let mut discard = Xml::discard();
+                        let body = sink.as_mut().unwrap_or(&mut discard);
+                        body.open("block-definition", &[("s", &block.0), ("type", &"command"), ("category", &"music")]).empty("inputs", &[]).open("script", &[]);
+                        translate_section(section, next_tempo, body, context)?;
+                        flush_pending(body, context)?;
+                        context.modifiers.set(&Default::default(), body);
+                        body.close().close();
+                        context.sink = context.sink.take().or(sink);
+                    }
+                    output.empty("custom-block", &[("s", &block.0)]);
+                }
+                false => {
+                    translate_section(section, next_tempo, output, context)?;
+                    flush_pending(output, context)?;
+                    context.modifiers.set(&Default::default(), output);
+                }
             }
         }
     }
+    context.path.pop();
 
-    write!(output, r#"</script></scripts></sprite>"#).unwrap();
+    output.close().close().close();
     Ok(())
 }
+// lets translate_into stream into files, stdout, etc. (the io error that stopped a translation can be recovered with take_error)
+#[cfg(feature = "std")]
+pub struct IoWriter<W: std::io::Write> {
+    inner: W,
+    error: Option<std::io::Error>,
+}
+#[cfg(feature = "std")]
+impl<W: std::io::Write> IoWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, error: None }
+    }
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+#[cfg(feature = "std")]
+impl<W: std::io::Write> fmt::Write for IoWriter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
 pub fn translate(composition: &Composition) -> Result<String, TranslateError> {
-    let composition = composition.restructure_staves_as_parts().flatten();
-    let title = xml_escape(composition.get_title());
+    translate_with_options(composition, &Default::default())
+}
+pub fn translate_with_options(composition: &Composition, options: &TranslateOptions) -> Result<String, TranslateError> {
+    translate_with_report(composition, options).map(|x| x.project)
+}
+pub fn translate_with_report(composition: &Composition, options: &TranslateOptions) -> Result<Translation, TranslateError> {
+    let mut project = String::new();
+    let warnings = translate_into(composition, options, &mut project)?;
+    Ok(Translation { project, warnings })
+}
+// writes the project as it is generated rather than building it in memory.
+// block definitions come after all the sprites, so parts are translated (at least) twice: once for the sprites and once for the blocks they use.
+pub fn translate_into<W: fmt::Write>(composition: &Composition, options: &TranslateOptions, output: &mut W) -> Result<Vec<TranslateWarning>, TranslateError> {
+    let parts = composition.iter().flat_map(|part| part.extract_staves_as_parts().into_iter().map(|x| (part.get_name(), x))).collect::<Vec<_>>();
+    let room_name = options.room_name.as_deref().unwrap_or(composition.get_title());
+    let role_name = &options.role_name;
+    let (stage_width, stage_height) = (options.stage_width, options.stage_height);
     let tempo = quarter_note_tempo(composition.get_tempo());
 
     let stringify_list = |x: &[String]| if !x.is_empty() { x.join(", ") } else { "N/A".into() };
-    let notes = xml_escape(&format!("title: {title}\ncomposers: {composers}\nlyricists: {lyricists}\narrangers: {arrangers}\npublisher: {publisher}\ncopyright: {copyright}\n\ntempo: {tempo}\ntime signature: {time_signature}\nkey: {key_sig:?}{key_mode:?}",
+    let notes = format!("title: {title}\ncomposers: {composers}\nlyricists: {lyricists}\narrangers: {arrangers}\npublisher: {publisher}\ncopyright: {copyright}\n\ntempo: {tempo}\ntime signature: {time_signature}\nkey: {key_sig:?}{key_mode:?}",
         title = composition.get_title(),
         composers = stringify_list(composition.get_composers()),
         lyricists = stringify_list(composition.get_lyricists()),
@@ -374,29 +1761,90 @@ pub fn translate(composition: &Composition) -> Result<String, TranslateError> {
         time_signature = composition.get_starting_time_signature(),
         key_sig = composition.get_starting_key().signature,
         key_mode = composition.get_starting_key().mode,
-    ));
+    );
 
-    let mut res = String::new();
-    write!(res, r#"<room name="{title}"><role name="myRole"><project name="myRole"><notes>{notes}</notes><stage name="Stage" width="480" height="360" costume="0" color="255,255,255,1" tempo="{tempo}" threadsafe="false" penlog="false" volume="100" pan="0" lines="round" ternary="false" hyperops="true" codify="false" inheritance="false" sublistIDs="false" scheduled="false"><costumes><list struct="atomic"></list></costumes><sounds><list struct="atomic"></list></sounds><variables></variables><blocks></blocks><messageTypes><messageType><name>message</name><fields><field>msg</field></fields></messageType></messageTypes><scripts></scripts><sprites>"#).unwrap();
+    let mut res = Xml::new(output, options.pretty);
+    res.open("room", &[("name", &room_name)]).open("role", &[("name", role_name)]).open("project", &[("name", role_name)]).leaf("notes", &[], notes);
+    res.open("stage", &[("name", &"Stage"), ("width", &stage_width), ("height", &stage_height), ("costume", &0), ("color", &"255,255,255,1"), ("tempo", &tempo), ("threadsafe", &false), ("penlog", &false), ("volume", &100), ("pan", &0), ("lines", &"round"), ("ternary", &false), ("hyperops", &true), ("codify", &false), ("inheritance", &false), ("sublistIDs", &false), ("scheduled", &false)]);
+    res.open("costumes", &[]).empty("list", &[("struct", &"atomic")]).close();
+    res.open("sounds", &[]).empty("list", &[("struct", &"atomic")]).close();
+    res.empty("variables", &[]).empty("blocks", &[]);
+    res.open("messageTypes", &[]).open("messageType", &[]).leaf("name", &[], "message").open("fields", &[]).leaf("field", &[], "msg").close().close().close();
+    res.empty("scripts", &[]).open("sprites", &[]);
 
-    let mut context = Context {
+    let new_context = |pass, blocks| Context {
+        options,
+        pass,
+        defining: String::new(),
+        sink: None,
         modifiers: <_>::default(),
         sections: <_>::default(),
         phrases: <_>::default(),
         staffs: <_>::default(),
+        multivoices: <_>::default(),
         starting_key: *composition.get_starting_key(),
+        key: *composition.get_starting_key(),
         starting_tempo: *composition.get_tempo(),
-        blocks: <_>::default(),
+        starting_time_signature: *composition.get_starting_time_signature(),
+        blocks,
+        grace_notes: <_>::default(),
+        tie: None,
+        glide: None,
+        next_dynamic: None,
+        octave_shift: 0,
+        transposition: Transposition::default(),
+        phrase_mods: <_>::default(),
+        position: 0.0,
+        tuplet_ratio: 1.0,
+        volume: 100.0,
+        target_volume: 100.0,
+        volume_ramp: None,
+        tempo: 0.0,
+        target_tempo: 0.0,
+        tempo_ramp: None,
+        tempo_scale: 1.0,
+        tempo_restore: false,
+        time_signature: *composition.get_starting_time_signature(),
+        measures: Measures::new(composition.get_starting_time_signature()),
+        iterations: <_>::default(),
+        path: <_>::default(),
+        warnings: <_>::default(),
     };
-    for part in composition.iter() {
-        translate_part(part, &mut res, &mut context)?;
+    let mut blocks = SharedBlocks::default();
+    if options.phrase_blocks {
+        let mut context = new_context(Pass::Count, blocks);
+        for (i, (source_name, part)) in parts.iter().enumerate() {
+            let position = sprite_position(options.sprite_layout, i, parts.len(), options);
+            translate_part(part, source_name, position, &mut Xml::discard(), &mut context)?;
+        }
+        blocks = context.blocks;
     }
 
-    write!(res, r#"</sprites></stage><blocks>"#).unwrap();
-    for block_def in context.blocks.values() {
-        res += block_def.as_str();
+    let mut context = new_context(Pass::Sprites, blocks);
+    for (i, (source_name, part)) in parts.iter().enumerate() {
+        let position = sprite_position(options.sprite_layout, i, parts.len(), options);
+        translate_part(part, source_name, position, &mut res, &mut context)?;
+        res.status().map_err(|_| TranslateError::WriteFailed)?;
+    }
+    let (blocks, warnings) = (context.blocks, context.warnings);
+
+    // each block definition gets a pass of its own, so that they can be written in order of name without holding any back
+    res.close().close().open("blocks", &[]);
+    if options.section_blocks || options.phrase_blocks {
+        for name in blocks.names.iter() {
+            let mut context = new_context(Pass::Blocks, blocks.clone());
+            context.defining = name.clone();
+            context.sink = Some(res);
+            for (i, (source_name, part)) in parts.iter().enumerate() {
+                let position = sprite_position(options.sprite_layout, i, parts.len(), options);
+                translate_part(part, source_name, position, &mut Xml::discard(), &mut context)?;
+            }
+            res = context.sink.take().unwrap();
+            res.status().map_err(|_| TranslateError::WriteFailed)?;
+        }
     }
-    write!(res, r#"</blocks><variables></variables></project><media name="myRole"></media></role></room>"#).unwrap();
+    res.close().empty("variables", &[]).close().empty("media", &[("name", role_name)]).close().close();
+    res.status().map_err(|_| TranslateError::WriteFailed)?;
 
-    Ok(res)
+    Ok(warnings)
 }
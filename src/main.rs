@@ -1,17 +1,75 @@
+use std::io::Write;
+
 use clap::Parser;
 
 #[derive(Parser)]
 struct Args {
-    /// Path to the input composition file
+    /// Path to the input composition file, read by extension: .musicxml and .xml as MusicXML, .mid and .smf as MIDI, anything else as AMM
+    /// (.xml files used to be read as AMM, so AMM files with that extension need to be renamed)
     path: String,
 
     /// Print the composition instead of the generated NetsBlox project
     #[clap(short, long)]
     composition: bool,
 
+    /// Read the input as a NetsBlox project generated by this tool, regardless of its extension
+    #[clap(long)]
+    project: bool,
+
     /// Use pretty print mode if applicable
     #[clap(short, long)]
     pretty: bool,
+
+    /// Override the instrument of a part (can be given multiple times)
+    #[clap(short, long = "instrument", value_name = "PART=INSTRUMENT")]
+    instruments: Vec<String>,
+
+    /// Annotate the first block of each measure with its measure number
+    #[clap(short, long)]
+    measure_numbers: bool,
+
+    /// Transpose all parts, or a single part, by a number of semitones (can be given multiple times)
+    #[clap(short, long = "transpose", value_name = "[PART=]SEMITONES", allow_hyphen_values = true)]
+    transpositions: Vec<String>,
+}
+
+struct MidiTrack {
+    name: Option<String>,
+    program: Option<u8>, // none for percussion tracks (channel 10)
+}
+
+// instrument of each track that actually plays notes, in track order
+fn midi_tracks(content: &[u8]) -> Vec<MidiTrack> {
+    let smf = match midly::Smf::parse(content) {
+        Ok(x) => x,
+        Err(_) => return vec![],
+    };
+
+    let mut res = vec![];
+    for track in smf.tracks.iter() {
+        let mut name = None;
+        let mut program = None;
+        let mut has_notes = false;
+        let mut percussion = false;
+        for event in track.iter() {
+            match event.kind {
+                midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(x)) => name = name.or_else(|| Some(String::from_utf8_lossy(x).into_owned())),
+                midly::TrackEventKind::Midi { channel, message } => match message {
+                    midly::MidiMessage::ProgramChange { program: x } => program = program.or(Some(x.as_int())),
+                    midly::MidiMessage::NoteOn { .. } => {
+                        has_notes = true;
+                        percussion |= channel.as_int() == 9;
+                    }
+                    _ => (),
+                }
+                _ => (),
+            }
+        }
+        if has_notes {
+            res.push(MidiTrack { name, program: if percussion { None } else { Some(program.unwrap_or(0)) } });
+        }
+    }
+    res
 }
 
 fn main() {
@@ -19,9 +77,20 @@ fn main() {
 
     let content = std::fs::read(&args.path).unwrap();
 
+    let mut tracks = vec![];
+    let mut instruments = Default::default();
     let composition = match args.path.rsplit('.').next().unwrap_or_default() {
-        "musicxml" => amm_sdk::storage::Storage::MusicXML.load_data(content).unwrap(),
-        "mid" | "smf" => amm_sdk::storage::Storage::MIDI.load_data(content).unwrap(),
+        _ if args.project => {
+            let project = amm_sdk_netsblox::parse_project(&String::from_utf8(content).unwrap()).unwrap();
+            instruments = project.instruments;
+            project.composition
+        }
+        // .xml used to fall through to AMM, but nearly every .xml score out there is MusicXML
+        "musicxml" | "xml" => amm_sdk::storage::Storage::MusicXML.load_data(content).unwrap(),
+        "mid" | "smf" => {
+            tracks = midi_tracks(&content);
+            amm_sdk::storage::Storage::MIDI.load_data(content).unwrap()
+        }
         _ => amm_sdk::storage::Storage::AMM.load_data(content).unwrap(),
     };
 
@@ -30,6 +99,54 @@ fn main() {
             true => println!("{composition:#?}"),
             false => println!("{composition:?}"),
         }
-        false => println!("{}", amm_sdk_netsblox::translate(&composition).unwrap()),
+        false => {
+            let mut options = amm_sdk_netsblox::TranslateOptions::default().with_measure_numbers(args.measure_numbers).with_pretty(args.pretty);
+            // tracks are matched to parts by name, falling back to track order only when there is one track per part
+            let parts = composition.iter().collect::<Vec<_>>();
+            if !tracks.is_empty() && tracks.len() != parts.len() {
+                eprintln!("warning: {} midi tracks play notes but the composition has {} parts, so only tracks named after a part are given an instrument", tracks.len(), parts.len());
+            }
+            for (i, part) in parts.iter().enumerate() {
+                let track = match tracks.iter().find(|x| x.name.as_deref() == Some(part.get_name())) {
+                    Some(x) => x,
+                    None if tracks.len() == parts.len() => &tracks[i],
+                    None => continue,
+                };
+                options = match track.program {
+                    Some(program) => options.with_part_program(part.get_name(), program),
+                    None => options.with_part_instrument(part.get_name(), "Drum Kit"),
+                };
+            }
+            for (sprite, instrument) in instruments {
+                options = options.with_part_instrument(sprite, instrument);
+            }
+            for instrument in args.instruments.iter() {
+                let (part, instrument) = instrument.split_once('=').expect("instrument overrides should be of the form PART=INSTRUMENT");
+                options = options.with_part_instrument(part, instrument);
+            }
+            for transposition in args.transpositions.iter() {
+                let (part, semitones) = match transposition.split_once('=') {
+                    Some((part, semitones)) => (Some(part), semitones),
+                    None => (None, transposition.as_str()),
+                };
+                let transposition = amm_sdk_netsblox::Transposition::semitones(semitones.parse().expect("transpositions should be a whole number of semitones"));
+                options = match part {
+                    Some(part) => options.with_part_transposition(part, transposition),
+                    None => options.with_transposition(transposition),
+                };
+            }
+
+            let mut output = amm_sdk_netsblox::IoWriter::new(std::io::BufWriter::new(std::io::stdout().lock()));
+            let warnings = match amm_sdk_netsblox::translate_into(&composition, &options, &mut output) {
+                Ok(x) => x,
+                Err(amm_sdk_netsblox::TranslateError::WriteFailed) => panic!("{}", output.take_error().unwrap()),
+                Err(e) => panic!("{e:?}"),
+            };
+            let mut output = output.into_inner();
+            writeln!(output).and_then(|_| output.flush()).unwrap();
+            for warning in warnings.iter() {
+                eprintln!("warning: {warning}");
+            }
+        }
     }
 }
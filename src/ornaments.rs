@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+
+use amm_sdk::note::{Note, Pitch, PitchName, Accidental};
+use amm_sdk::context::Key;
+use amm_sdk::modification::NoteModificationType;
+
+use crate::{GlissandoStyle, TICKS_PER_WHOLE};
+
+const SIXTY_FOURTH: u32 = TICKS_PER_WHOLE / 64;
+const THIRTY_SECOND: u32 = TICKS_PER_WHOLE / 32;
+
+pub(crate) const LETTERS: [PitchName; 7] = [PitchName::C, PitchName::D, PitchName::E, PitchName::F, PitchName::G, PitchName::A, PitchName::B];
+const SHARPS: [PitchName; 7] = [PitchName::F, PitchName::C, PitchName::G, PitchName::D, PitchName::A, PitchName::E, PitchName::B];
+const FLATS: [PitchName; 7] = [PitchName::B, PitchName::E, PitchName::A, PitchName::D, PitchName::G, PitchName::C, PitchName::F];
+const CHROMATIC: [(PitchName, PitchName); 12] = [
+    (PitchName::C, PitchName::C), (PitchName::C, PitchName::D), (PitchName::D, PitchName::D), (PitchName::D, PitchName::E),
+    (PitchName::E, PitchName::E), (PitchName::F, PitchName::F), (PitchName::F, PitchName::G), (PitchName::G, PitchName::G),
+    (PitchName::G, PitchName::A), (PitchName::A, PitchName::A), (PitchName::A, PitchName::B), (PitchName::B, PitchName::B),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ornament {
+    Trill { upper: bool },
+    Mordent { upper: bool },
+}
+impl Ornament {
+    pub(crate) fn from_modification(modification: &NoteModificationType) -> Option<Self> {
+        match modification {
+            NoteModificationType::Trill { upper } => Some(Self::Trill { upper: *upper }),
+            NoteModificationType::Shake => Some(Self::Trill { upper: true }),
+            NoteModificationType::Mordent { upper } => Some(Self::Mordent { upper: *upper }),
+            _ => None,
+        }
+    }
+
+    // diatonic step (relative to the principal note) and length in ticks of each note the ornament expands into
+    pub(crate) fn expand(self, ticks: u32) -> Option<Vec<(i8, u32)>> {
+        let neighbor = |upper: bool| if upper { 1 } else { -1 };
+        match self {
+            Self::Trill { upper } => {
+                // alternate in 32nd notes (64th for short notes), ending on the principal note which absorbs any leftover time
+                let unit = if ticks >= 4 * THIRTY_SECOND { THIRTY_SECOND } else { SIXTY_FOURTH };
+                let count = match ticks / unit {
+                    x if x < 3 => return None,
+                    x => x - (1 - x % 2),
+                };
+                let mut res = (0..count).map(|i| (if i % 2 == 0 { 0 } else { neighbor(upper) }, unit)).collect::<Vec<_>>();
+                res.last_mut().unwrap().1 += ticks - count * unit;
+                Some(res)
+            }
+            Self::Mordent { upper } => {
+                let unit = (ticks / 4 / SIXTY_FOURTH * SIXTY_FOURTH).min(THIRTY_SECOND);
+                if unit == 0 {
+                    return None;
+                }
+                Some(vec![(0, unit), (neighbor(upper), unit), (0, ticks - 2 * unit)])
+            }
+        }
+    }
+}
+
+fn key_accidental(name: PitchName, key: &Key) -> Accidental {
+    match key.fifths() {
+        x if x > 0 && SHARPS.iter().take(x as usize).any(|x| *x == name) => Accidental::Sharp,
+        x if x < 0 && FLATS.iter().take(x.unsigned_abs() as usize).any(|x| *x == name) => Accidental::Flat,
+        _ => Accidental::None,
+    }
+}
+fn diatonic_index(pitch: Pitch) -> Option<i16> {
+    Some(i16::from(pitch.octave) * 7 + LETTERS.iter().position(|x| *x == pitch.name)? as i16)
+}
+
+// the note a number of diatonic steps away, with the accidental implied by the key
+pub(crate) fn diatonic_neighbor(pitch: Pitch, steps: i8, key: &Key) -> Option<(Pitch, Accidental)> {
+    let total = u8::try_from(diatonic_index(pitch)? + i16::from(steps)).ok()?;
+    let name = LETTERS[(total % 7) as usize];
+    Some((Pitch::new(name, total / 7), key_accidental(name, key)))
+}
+
+// the note with a given midi number, spelled with sharps (or flats) and naturals wherever the key would alter it
+fn chromatic_pitch(midi: u8, sharps: bool, key: &Key) -> Option<(Pitch, Accidental)> {
+    let (sharp, flat) = CHROMATIC[(midi % 12) as usize];
+    let name = if sharps { sharp } else { flat };
+    let accidental = match (sharp == flat, sharps) {
+        (false, true) => Accidental::Sharp,
+        (false, false) => Accidental::Flat,
+        (true, _) => match key_accidental(name, key) {
+            Accidental::None => Accidental::None,
+            _ => Accidental::Natural,
+        },
+    };
+    Some((Pitch::new(name, (midi / 12).checked_sub(1)?), accidental))
+}
+
+// pitch and length in ticks of each note of a run sliding from one note towards (but not including) the next.
+// the run is spread evenly over the (64th note) grid, with the written note held for any leftover time.
+pub(crate) fn slide(from: &Note, to: &Note, style: GlissandoStyle, key: &Key, ticks: u32) -> Option<Vec<((Pitch, Accidental), u32)>> {
+    let run = match style {
+        GlissandoStyle::Off => return None,
+        GlissandoStyle::Chromatic => {
+            let (a, b) = (from.midi_number(Some(*key)), to.midi_number(Some(*key)));
+            match a < b {
+                true => (a + 1..b).map(|x| chromatic_pitch(x, true, key)).collect::<Option<Vec<_>>>()?,
+                false => (b + 1..a).rev().map(|x| chromatic_pitch(x, false, key)).collect::<Option<Vec<_>>>()?,
+            }
+        }
+        GlissandoStyle::Diatonic => {
+            let steps = i8::try_from(diatonic_index(to.pitch)? - diatonic_index(from.pitch)?).ok()?;
+            let run = match steps > 0 {
+                true => (1..steps).collect::<Vec<_>>(),
+                false => (steps + 1..0).rev().collect(),
+            };
+            run.into_iter().map(|x| diatonic_neighbor(from.pitch, x, key)).collect::<Option<Vec<_>>>()?
+        }
+    };
+
+    // runs that don't fit are thinned out evenly
+    let room = (ticks / SIXTY_FOURTH).checked_sub(1)? as usize;
+    let run = match run.len() > room {
+        true => (0..room).map(|i| run[(i + 1) * run.len() / (room + 1)]).collect(),
+        false => run,
+    };
+    if run.is_empty() {
+        return None;
+    }
+
+    let unit = ticks / (run.len() as u32 + 1) / SIXTY_FOURTH * SIXTY_FOURTH;
+    let mut res = vec![((from.pitch, from.accidental), ticks - unit * run.len() as u32)];
+    res.extend(run.into_iter().map(|x| (x, unit)));
+    Some(res)
+}
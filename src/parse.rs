@@ -0,0 +1,725 @@
+use core::iter;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use amm_sdk::Composition;
+use amm_sdk::note::{Note, Pitch, PitchName, Duration, DurationType, Accidental};
+use amm_sdk::context::{Key, KeyMode, KeySignature, Tempo, Dynamic, TimeSignature, TimeSignatureType};
+use amm_sdk::modification::{PhraseModificationType, NoteModificationType, SectionModificationType, DirectionType};
+use amm_sdk::structure::{Section, Staff, Phrase, Chord, MultiVoice, SectionContent, PhraseContent};
+
+use crate::{duration_ticks, dynamic_volume, parse_duration, quarter_note_tempo};
+
+const DURATION_TYPES: [DurationType; 15] = [
+    DurationType::Maxima, DurationType::Long, DurationType::Breve, DurationType::Whole, DurationType::Half, DurationType::Quarter, DurationType::Eighth, DurationType::Sixteenth,
+    DurationType::ThirtySecond, DurationType::SixtyFourth, DurationType::OneHundredTwentyEighth, DurationType::TwoHundredFiftySixth, DurationType::FiveHundredTwelfth,
+    DurationType::OneThousandTwentyFourth, DurationType::TwoThousandFortyEighth,
+];
+const KEY_SIGNATURES: [KeySignature; 18] = [
+    KeySignature::A, KeySignature::ASharp, KeySignature::AFlat, KeySignature::B, KeySignature::BFlat, KeySignature::C, KeySignature::CSharp, KeySignature::CFlat, KeySignature::D,
+    KeySignature::DSharp, KeySignature::DFlat, KeySignature::E, KeySignature::EFlat, KeySignature::F, KeySignature::FSharp, KeySignature::G, KeySignature::GSharp, KeySignature::GFlat,
+];
+const DYNAMICS: [Dynamic; 12] = [
+    Dynamic::Piano(5), Dynamic::Piano(4), Dynamic::Piano(3), Dynamic::Piano(2), Dynamic::Piano(1), Dynamic::MezzoPiano,
+    Dynamic::MezzoForte, Dynamic::Forte(1), Dynamic::Forte(2), Dynamic::Forte(3), Dynamic::Forte(4), Dynamic::Forte(5),
+];
+
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidXml { position: usize },
+    MissingElement { name: &'static str },
+    UnsupportedBlock { selector: String },
+    UnsupportedDirection { direction: DirectionType }, // e.g., a key change inside a phrase
+    InvalidValue { value: String },
+}
+
+#[derive(Debug)]
+pub struct ParsedProject {
+    pub composition: Composition,
+    pub instruments: BTreeMap<String, String>,
+}
+
+#[derive(PartialEq)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+#[derive(PartialEq)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|x| x.0 == name).map(|x| x.1.as_str())
+    }
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|x| match x {
+            Node::Element(x) => Some(x),
+            Node::Text(_) => None,
+        })
+    }
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|x| x.name == name)
+    }
+    fn find(&self, name: &str) -> Option<&Element> {
+        match self.name == name {
+            true => Some(self),
+            false => self.elements().find_map(|x| x.find(name)),
+        }
+    }
+    fn text(&self) -> String {
+        self.children.iter().map(|x| match x {
+            Node::Text(x) => x.as_str(),
+            Node::Element(_) => "",
+        }).collect()
+    }
+    fn selector(&self) -> &str {
+        self.attribute("s").unwrap_or_default()
+    }
+    // block inputs, skipping any attached comment (e.g., measure numbers)
+    fn input(&self, index: usize) -> Result<&Element, ParseError> {
+        self.elements().filter(|x| x.name != "comment").nth(index).ok_or(ParseError::MissingElement { name: "l" })
+    }
+    fn list(&self) -> Result<&Element, ParseError> {
+        self.child("list").ok_or(ParseError::MissingElement { name: "list" })
+    }
+    fn script(&self) -> Result<&Element, ParseError> {
+        self.child("script").ok_or(ParseError::MissingElement { name: "script" })
+    }
+}
+
+fn xml_unescape(input: &str, position: usize) -> Result<String, ParseError> {
+    let mut res = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('&') {
+        res.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or(ParseError::InvalidXml { position })? + start;
+        res.push(match &rest[start + 1..end] {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "apos" => '\'',
+            "quot" => '"',
+            x => {
+                let code = match x.strip_prefix("#x") {
+                    Some(x) => u32::from_str_radix(x, 16).ok(),
+                    None => x.strip_prefix('#').and_then(|x| x.parse().ok()),
+                };
+                match code.and_then(char::from_u32).ok_or(ParseError::InvalidXml { position })? {
+                    '\r' => '\n', // the translator escapes newlines as carriage returns
+                    x => x,
+                }
+            }
+        });
+        rest = &rest[end + 1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+// a minimal reader for the subset of xml used by NetsBlox projects (no doctypes, cdata, namespaces, etc.)
+fn parse_xml(input: &str) -> Result<Element, ParseError> {
+    let mut stack = vec![Element { name: String::new(), attributes: vec![], children: vec![] }];
+    let mut pos = 0;
+    while pos < input.len() {
+        let rest = &input[pos..];
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if !rest[..end].trim().is_empty() {
+                stack.last_mut().unwrap().children.push(Node::Text(xml_unescape(&rest[..end], pos)?));
+            }
+            pos += end;
+            continue;
+        }
+        if let Some((_, close)) = [("<?", "?>"), ("<!--", "-->")].into_iter().find(|x| rest.starts_with(x.0)) {
+            pos += rest.find(close).ok_or(ParseError::InvalidXml { position: pos })? + close.len();
+            continue;
+        }
+
+        let end = rest.find('>').ok_or(ParseError::InvalidXml { position: pos })?;
+        let tag = &rest[1..end];
+        if let Some(name) = tag.strip_prefix('/') {
+            let element = stack.pop().filter(|x| x.name == name.trim() && !stack.is_empty()).ok_or(ParseError::InvalidXml { position: pos })?;
+            stack.last_mut().unwrap().children.push(Node::Element(element));
+        } else {
+            let (tag, closed) = match tag.strip_suffix('/') {
+                Some(x) => (x, true),
+                None => (tag, false),
+            };
+            let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+            let mut element = Element { name: tag[..name_end].into(), attributes: vec![], children: vec![] };
+            let mut attributes = tag[name_end..].trim_start();
+            while !attributes.is_empty() {
+                let (name, value) = attributes.split_once('=').ok_or(ParseError::InvalidXml { position: pos })?;
+                let value = value.trim_start();
+                let quote = value.chars().next().filter(|x| *x == '"' || *x == '\'').ok_or(ParseError::InvalidXml { position: pos })?;
+                let value_end = value[1..].find(quote).ok_or(ParseError::InvalidXml { position: pos })? + 1;
+                element.attributes.push((name.trim().into(), xml_unescape(&value[1..value_end], pos)?));
+                attributes = value[value_end + 1..].trim_start();
+            }
+            match closed {
+                true => stack.last_mut().unwrap().children.push(Node::Element(element)),
+                false => stack.push(element),
+            }
+        }
+        pos += end + 1;
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(mut root), true) => match root.children.pop() {
+            Some(Node::Element(x)) => Ok(x),
+            _ => Err(ParseError::MissingElement { name: "room" }),
+        }
+        _ => Err(ParseError::InvalidXml { position: input.len() }),
+    }
+}
+
+fn invalid(value: &str) -> ParseError {
+    ParseError::InvalidValue { value: value.into() }
+}
+fn parse_number(element: &Element) -> Result<f64, ParseError> {
+    let text = element.text();
+    text.trim().parse().map_err(|_| invalid(&text))
+}
+fn parse_pitch(value: &str) -> Result<(Pitch, Accidental), ParseError> {
+    if value == "rest" {
+        return Ok((Pitch::new_rest(), Accidental::None));
+    }
+    let name = match value.chars().next() {
+        Some('A') => PitchName::A,
+        Some('B') => PitchName::B,
+        Some('C') => PitchName::C,
+        Some('D') => PitchName::D,
+        Some('E') => PitchName::E,
+        Some('F') => PitchName::F,
+        Some('G') => PitchName::G,
+        _ => return Err(invalid(value)),
+    };
+    let octave_end = value[1..].find(|c: char| !c.is_ascii_digit()).map(|x| x + 1).unwrap_or(value.len());
+    let octave = value[1..octave_end].parse().map_err(|_| invalid(value))?;
+    let accidental = match &value[octave_end..] {
+        "" => Accidental::None,
+        "n" => Accidental::Natural,
+        "s" => Accidental::Sharp,
+        "ss" => Accidental::DoubleSharp,
+        "b" => Accidental::Flat,
+        "bb" => Accidental::DoubleFlat,
+        _ => return Err(invalid(value)),
+    };
+    Ok((Pitch::new(name, octave), accidental))
+}
+fn parse_key(value: &str) -> Result<Key, ParseError> {
+    KEY_SIGNATURES.iter().flat_map(|signature| [KeyMode::Major, KeyMode::Minor].map(|mode| Key::new(*signature, mode)))
+        .find(|key| format!("{:?}{:?}", key.signature, key.mode) == value).ok_or_else(|| invalid(value))
+}
+fn parse_time_signature(value: &str) -> Result<TimeSignature, ParseError> {
+    Ok(match value {
+        "Common Time" => TimeSignature::new(TimeSignatureType::CommonTime),
+        "Cut Time" => TimeSignature::new(TimeSignatureType::CutTime),
+        "Senza Misura" => TimeSignature::new(TimeSignatureType::None),
+        x => {
+            let (numerator, denominator) = x.split_once('/').ok_or_else(|| invalid(x))?;
+            TimeSignature::new_explicit(numerator.parse().map_err(|_| invalid(x))?, denominator.parse().map_err(|_| invalid(x))?)
+        }
+    })
+}
+// tempos are written in quarter notes per minute, so fractional ones need a shorter beat to be a whole number
+fn parse_tempo(bpm: f64) -> Tempo {
+    let quarter = Duration::new(DurationType::Quarter, 0);
+    DURATION_TYPES[5..].iter().map(|x| Tempo::new(Duration::new(*x, 0), (bpm * quarter.value() / Duration::new(*x, 0).value()).clamp(1.0, u16::MAX.into()) as u16))
+        .find(|x| quarter_note_tempo(x) == bpm).unwrap_or_else(|| Tempo::new(quarter, bpm.round().clamp(1.0, u16::MAX.into()) as u16))
+}
+fn all_durations() -> impl Iterator<Item = Duration> {
+    (0..8).flat_map(|dots| DURATION_TYPES.iter().map(move |x| Duration::new(*x, dots)))
+}
+// the tied segments of a duration input, where ties that just spell out a single long (or many-dotted) duration are merged back into it
+fn parse_durations(element: &Element) -> Result<Vec<Duration>, ParseError> {
+    match (element.name.as_str(), element.selector()) {
+        ("l", _) => {
+            let text = element.text();
+            let (dots, name) = match (text.strip_prefix("DottedDotted"), text.strip_prefix("Dotted")) {
+                (Some(x), _) => (2, x),
+                (None, Some(x)) => (1, x),
+                (None, None) => (0, text.as_str()),
+            };
+            let value = DURATION_TYPES.iter().copied().find(|x| format!("{x:?}") == name).ok_or_else(|| invalid(&text))?;
+            Ok(vec![Duration::new(value, dots)])
+        }
+        ("block", "tieDuration") => {
+            let mut res = vec![];
+            for x in element.list()?.elements() {
+                res.extend(parse_durations(x)?);
+            }
+            let ticks = res.iter().map(|x| duration_ticks(*x)).sum::<u32>();
+            let merged = all_durations().filter(|x| duration_ticks(*x) == ticks)
+                .find(|x| parse_duration(*x).ok().and_then(|x| parse_xml(&String::from(x)).ok()).as_ref() == Some(element));
+            Ok(merged.map(|x| vec![x]).unwrap_or(res))
+        }
+        (_, x) => Err(ParseError::UnsupportedBlock { selector: x.into() }),
+    }
+}
+// volumes only come from dynamics (or ramps between them), so we take the closest one
+fn volume_dynamic(volume: f64) -> Dynamic {
+    DYNAMICS.iter().copied().min_by(|a, b| (dynamic_volume(a) - volume).abs().total_cmp(&(dynamic_volume(b) - volume).abs())).unwrap()
+}
+
+enum StaffItem {
+    Chord(Vec<Note>),
+    Phrase(Vec<PhraseModificationType>, Vec<StaffItem>),
+    MultiVoice(Vec<Vec<StaffItem>>),
+    Direction(DirectionType),
+}
+enum SectionItem {
+    Staff(String, Vec<StaffItem>),
+    Section(String, Vec<SectionModificationType>, Vec<SectionItem>),
+}
+
+struct State<'a> {
+    definitions: &'a BTreeMap<&'a str, &'a Element>,
+    visiting: BTreeSet<&'a str>,
+    staff: String,
+    sections: usize,
+    note_mods: Vec<NoteModificationType>,
+    dynamic: Option<Dynamic>,
+}
+impl State<'_> {
+    fn section_name(&mut self, parent: &str) -> String {
+        self.sections += 1;
+        format!("{parent}.{}", self.sections)
+    }
+    fn push(&self, items: &mut Vec<SectionItem>, item: StaffItem) {
+        match items.last_mut() {
+            Some(SectionItem::Staff(_, x)) => x.push(item),
+            _ => items.push(SectionItem::Staff(self.staff.clone(), vec![item])),
+        }
+    }
+    fn extend(&self, items: &mut Vec<SectionItem>, new_items: Vec<SectionItem>) {
+        for item in new_items {
+            match item {
+                SectionItem::Staff(_, x) => for x in x { self.push(items, x) },
+                x => items.push(x),
+            }
+        }
+    }
+}
+
+fn last_chord(items: &mut [SectionItem]) -> Option<&mut Vec<Note>> {
+    fn last_staff_chord(items: &mut [StaffItem]) -> Option<&mut Vec<Note>> {
+        match items.last_mut()? {
+            StaffItem::Chord(x) => Some(x),
+            StaffItem::Phrase(_, x) => last_staff_chord(x),
+            StaffItem::MultiVoice(_) | StaffItem::Direction(_) => None,
+        }
+    }
+    match items.last_mut()? {
+        SectionItem::Staff(_, x) => last_staff_chord(x),
+        SectionItem::Section(_, _, x) => last_chord(x),
+    }
+}
+// phrases can't hold directions or cross section boundaries, so a phrase mod block may need several phrases
+fn wrap_phrases(items: Vec<SectionItem>, mods: &[PhraseModificationType]) -> Vec<SectionItem> {
+    items.into_iter().map(|item| match item {
+        SectionItem::Staff(name, items) => {
+            let mut res = vec![];
+            let mut phrase = vec![];
+            for item in items {
+                match item {
+                    StaffItem::Direction(x) => {
+                        if !phrase.is_empty() {
+                            res.push(StaffItem::Phrase(mods.to_vec(), core::mem::take(&mut phrase)));
+                        }
+                        res.push(StaffItem::Direction(x));
+                    }
+                    x => phrase.push(x),
+                }
+            }
+            if !phrase.is_empty() {
+                res.push(StaffItem::Phrase(mods.to_vec(), phrase));
+            }
+            SectionItem::Staff(name, res)
+        }
+        SectionItem::Section(name, section_mods, items) => SectionItem::Section(name, section_mods, wrap_phrases(items, mods)),
+    }).collect()
+}
+
+fn parse_chords(durations: &Element, pitches: &[(Pitch, Accidental)], state: &mut State) -> Result<Vec<StaffItem>, ParseError> {
+    // notes with their own durations are played together, so each has to be (or is approximated by) a single note value
+    let durations = match durations.selector() {
+        "reportNewList" => {
+            let mut res = vec![];
+            for x in durations.list()?.elements() {
+                let segments = parse_durations(x)?;
+                let ticks = segments.iter().map(|x| duration_ticks(*x)).sum::<u32>();
+                match segments.as_slice() {
+                    [x] => res.push(vec![*x]),
+                    _ => res.push(vec![all_durations().min_by_key(|x| duration_ticks(*x).abs_diff(ticks)).unwrap()]),
+                }
+            }
+            if res.len() != pitches.len() {
+                return Err(invalid(&format!("{} durations for {} notes", res.len(), pitches.len())));
+            }
+            res
+        }
+        _ => vec![parse_durations(durations)?; pitches.len()],
+    };
+
+    let segments = durations.iter().map(Vec::len).max().unwrap_or(0);
+    let mut res = vec![];
+    for segment in 0..segments {
+        let mut chord = vec![];
+        for ((pitch, accidental), durations) in iter::zip(pitches, &durations) {
+            let mut note = Note::new(*pitch, durations[segment], Some(*accidental));
+            if !pitch.is_rest() {
+                for m in state.note_mods.iter() {
+                    note.add_modification(*m);
+                }
+                if segment + 1 < segments {
+                    note.add_modification(NoteModificationType::Tie);
+                }
+            }
+            chord.push(note);
+        }
+        if let (Some(dynamic), Some(note)) = (state.dynamic, chord.first_mut()) {
+            note.add_modification(NoteModificationType::Dynamic { dynamic });
+            state.dynamic = None;
+        }
+        res.push(StaffItem::Chord(chord));
+    }
+    Ok(res)
+}
+fn parse_blocks<'a>(blocks: &[&'a Element], name: &str, state: &mut State<'a>) -> Result<Vec<SectionItem>, ParseError> {
+    let mut items = vec![];
+    let mut i = 0;
+    while i < blocks.len() {
+        let block = blocks[i];
+        i += 1;
+
+        if block.name == "custom-block" {
+            let definition = *state.definitions.get(block.selector()).ok_or_else(|| ParseError::UnsupportedBlock { selector: block.selector().into() })?;
+            let block_name = definition.selector();
+            if !state.visiting.insert(block_name) {
+                return Err(ParseError::UnsupportedBlock { selector: block_name.into() });
+            }
+            // each staff defines its own copy of a section block, numbered after the first
+            let section_name = match block_name.rsplit_once(' ') {
+                Some((prefix, suffix)) if suffix.parse::<usize>().is_ok() && state.definitions.contains_key(prefix) => prefix,
+                _ => block_name,
+            };
+            let section = parse_script(definition.script()?, section_name, state)?;
+            state.visiting.remove(block_name);
+            items.push(SectionItem::Section(section_name.into(), vec![], section));
+            continue;
+        }
+
+        match block.selector() {
+            "playNotes" => {
+                let pitches = block.input(1)?.elements().map(|x| parse_pitch(x.text().trim())).collect::<Result<Vec<_>, _>>()?;
+                for chord in parse_chords(block.input(0)?, &pitches, state)? {
+                    state.push(&mut items, chord);
+                }
+            }
+            "rest" => {
+                for chord in parse_chords(block.input(0)?, &[(Pitch::new_rest(), Accidental::None)], state)? {
+                    state.push(&mut items, chord);
+                }
+            }
+            "setKey" => state.push(&mut items, StaffItem::Direction(DirectionType::KeyChange { key: parse_key(block.input(0)?.text().trim())? })),
+            "setTimeSignature" => {
+                let (numerator, denominator) = (parse_number(block.input(0)?)?, parse_number(block.input(1)?)?);
+                state.push(&mut items, StaffItem::Direction(DirectionType::TimeSignatureChange { time_signature: TimeSignature::new_explicit(numerator as u8, denominator as u8) }));
+            }
+            "setAudioEffect" => match block.input(0)?.text().trim() {
+                "Volume" => state.dynamic = Some(volume_dynamic(parse_number(block.input(1)?)?)),
+                x => return Err(invalid(x)),
+            }
+            "setBPM" => {
+                // a tempo change applies to everything up to the next one, each of which starts a section of its own
+                let tempo = parse_tempo(parse_number(block.input(0)?)?);
+                let end = blocks[i..].iter().position(|x| x.name == "block" && x.selector() == "setBPM").map_or(blocks.len(), |x| i + x);
+                let name = state.section_name(name);
+                let section = parse_blocks(&blocks[i..end], &name, state)?;
+                items.push(SectionItem::Section(name, vec![SectionModificationType::TempoExplicit { tempo }], section));
+                i = end;
+            }
+            "noteMod" => {
+                let mut note_mods = vec![];
+                let mut phrase_mods = vec![];
+                for option in block.list()?.elements() {
+                    let option = option.child("option").map(Element::text).unwrap_or_else(|| option.text());
+                    match option.as_str() {
+                        "Accent" => note_mods.push(NoteModificationType::Accent),
+                        "Staccato" => note_mods.push(NoteModificationType::Staccato),
+                        "TurnUpper" => note_mods.push(NoteModificationType::Turn { upper: true, delayed: false, vertical: false }),
+                        "TurnLower" => note_mods.push(NoteModificationType::Turn { upper: false, delayed: false, vertical: false }),
+                        "Legato" => phrase_mods.push(PhraseModificationType::Legato),
+                        x => {
+                            let ratio = x.strip_prefix("Tuplet ").and_then(|x| x.split_once(':')).and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)));
+                            let (num_beats, into_beats) = ratio.ok_or_else(|| invalid(x))?;
+                            phrase_mods.push(PhraseModificationType::Tuplet { num_beats, into_beats });
+                        }
+                    }
+                }
+
+                let point = state.note_mods.len();
+                state.note_mods.extend(note_mods);
+                let inner = parse_script(block.script()?, name, state)?;
+                state.note_mods.truncate(point);
+
+                match phrase_mods.is_empty() {
+                    true => state.extend(&mut items, inner),
+                    false => state.extend(&mut items, wrap_phrases(inner, &phrase_mods)),
+                }
+            }
+            "doRepeat" => {
+                let times = block.input(0)?.text();
+                let times = times.trim().parse::<u16>().ok().and_then(|x| u8::try_from(x.saturating_sub(1)).ok()).ok_or_else(|| invalid(&times))?;
+                let name = state.section_name(name);
+                let section = parse_script(block.input(1)?, &name, state)?;
+                let mods = match times {
+                    0 => vec![],
+                    num_times => vec![SectionModificationType::Repeat { num_times }],
+                };
+                items.push(SectionItem::Section(name, mods, section));
+            }
+            "doIf" => {
+                // endings check the iteration counter of their repeat
+                let condition = block.input(0)?;
+                if condition.selector() != "reportListContainsItem" {
+                    return Err(ParseError::UnsupportedBlock { selector: condition.selector().into() });
+                }
+                let iterations = condition.input(0)?.list()?.elements().map(|x| parse_number(x).map(|x| x as u8)).collect::<Result<Vec<_>, _>>()?;
+                let name = state.section_name(name);
+                let section = parse_script(block.input(1)?, &name, state)?;
+                items.push(SectionItem::Section(name, vec![SectionModificationType::OnlyPlay { iterations }], section));
+            }
+            "fork" => {
+                // consecutive forks are the voices of a multivoice, rejoined by waiting on their counter afterwards
+                let mut voices = vec![];
+                for block in iter::once(block).chain(blocks[i..].iter().copied().take_while(|x| x.selector() == "fork")) {
+                    let mut voice = vec![];
+                    for item in parse_script(block.input(0)?.script()?, name, state)? {
+                        match item {
+                            SectionItem::Staff(_, x) => voice.extend(x),
+                            SectionItem::Section(..) => return Err(ParseError::UnsupportedBlock { selector: "fork".into() }),
+                        }
+                    }
+                    voices.push(voice);
+                }
+                i += voices.len() - 1;
+                state.push(&mut items, StaffItem::MultiVoice(voices));
+            }
+            "doWait" => {
+                // fermatas can be held by waiting after the note
+                if let Some(chord) = last_chord(&mut items) {
+                    for note in chord.iter_mut() {
+                        note.add_modification(NoteModificationType::Fermata);
+                    }
+                }
+            }
+            "doDeclareVariables" | "doSetVar" | "doChangeVar" | "doWaitUntil" => (), // repeat and voice counters
+            x => return Err(ParseError::UnsupportedBlock { selector: x.into() }),
+        }
+    }
+    Ok(items)
+}
+fn script_blocks(script: &Element) -> Vec<&Element> {
+    script.elements().filter(|x| x.name == "block" || x.name == "custom-block").collect()
+}
+fn parse_script<'a>(script: &'a Element, name: &str, state: &mut State<'a>) -> Result<Vec<SectionItem>, ParseError> {
+    parse_blocks(&script_blocks(script), name, state)
+}
+
+// sections are aligned between the staves of a part, with the staff content in between them played side by side
+fn merge_items(a: Vec<SectionItem>, b: Vec<SectionItem>) -> Vec<SectionItem> {
+    fn split(items: Vec<SectionItem>) -> Vec<(Vec<SectionItem>, Option<SectionItem>)> {
+        let mut res = vec![(vec![], None)];
+        for item in items {
+            match item {
+                x @ SectionItem::Staff(..) => res.last_mut().unwrap().0.push(x),
+                x => {
+                    res.last_mut().unwrap().1 = Some(x);
+                    res.push((vec![], None));
+                }
+            }
+        }
+        res
+    }
+
+    let (mut a, mut b) = (split(a).into_iter(), split(b).into_iter());
+    let mut res = vec![];
+    loop {
+        let (x, y) = match (a.next(), b.next()) {
+            (None, None) => break,
+            (x, y) => (x.unwrap_or_default(), y.unwrap_or_default()),
+        };
+        res.extend(x.0);
+        res.extend(y.0);
+        match (x.1, y.1) {
+            (Some(SectionItem::Section(name, mods, x)), Some(SectionItem::Section(other_name, other_mods, y))) if name == other_name && mods == other_mods => res.push(SectionItem::Section(name, mods, merge_items(x, y))),
+            (x, y) => res.extend(x.into_iter().chain(y)),
+        }
+    }
+    res
+}
+
+fn build_content(item: StaffItem) -> Result<PhraseContent, ParseError> {
+    Ok(match item {
+        StaffItem::Chord(mut notes) if notes.len() == 1 => PhraseContent::Note(notes.pop().unwrap()),
+        StaffItem::Chord(notes) => {
+            let mut chord = Chord::new();
+            for note in notes {
+                chord.claim_note(note);
+            }
+            PhraseContent::Chord(chord)
+        }
+        StaffItem::Phrase(mods, items) => PhraseContent::Phrase(build_phrase(&mods, items)?),
+        StaffItem::MultiVoice(voices) => {
+            let mut multivoice = MultiVoice::new();
+            for voice in voices {
+                multivoice.claim_phrase(build_phrase(&[], voice)?);
+            }
+            PhraseContent::MultiVoice(multivoice)
+        }
+        // directions can only be kept between the contents of a staff
+        StaffItem::Direction(direction) => return Err(ParseError::UnsupportedDirection { direction }),
+    })
+}
+fn build_phrase(mods: &[PhraseModificationType], items: Vec<StaffItem>) -> Result<Phrase, ParseError> {
+    let mut phrase = Phrase::new();
+    for m in mods {
+        phrase.add_modification(*m);
+    }
+    for item in items {
+        phrase.claim(build_content(item)?);
+    }
+    Ok(phrase)
+}
+fn build_section(name: &str, mods: Vec<SectionModificationType>, items: Vec<SectionItem>) -> Result<Section, ParseError> {
+    let mut section = Section::new(name);
+    for m in mods {
+        section.add_modification(m);
+    }
+    for item in items {
+        match item {
+            SectionItem::Staff(name, items) => {
+                let mut staff = Staff::new(&name);
+                for item in items {
+                    match item {
+                        StaffItem::Direction(x) => _ = staff.add_direction(x),
+                        x => match build_content(x)? {
+                            PhraseContent::Note(x) => _ = staff.claim_note(x),
+                            PhraseContent::Chord(x) => _ = staff.claim_chord(x),
+                            PhraseContent::Phrase(x) => _ = staff.claim_phrase(x),
+                            PhraseContent::MultiVoice(x) => _ = staff.claim_multivoice(x),
+                        }
+                    }
+                }
+                section.claim(SectionContent::Staff(staff));
+            }
+            SectionItem::Section(name, mods, items) => _ = section.claim_section(build_section(&name, mods, items)?),
+        }
+    }
+    Ok(section)
+}
+
+pub fn parse_project(xml: &str) -> Result<ParsedProject, ParseError> {
+    let root = parse_xml(xml)?;
+    let project = root.find("project").ok_or(ParseError::MissingElement { name: "project" })?;
+    let sprites = project.find("sprites").ok_or(ParseError::MissingElement { name: "sprites" })?.elements().filter(|x| x.name == "sprite").collect::<Vec<_>>();
+    let definitions = project.child("blocks").into_iter().flat_map(Element::elements).filter(|x| x.name == "block-definition").map(|x| (x.selector(), x)).collect::<BTreeMap<_, _>>();
+    let notes = project.child("notes").map(Element::text).unwrap_or_default();
+    let notes = notes.lines().filter_map(|x| x.split_once(':')).map(|(k, v)| (k.trim(), v.trim())).collect::<BTreeMap<_, _>>();
+
+    let scripts = sprites.iter().map(|sprite| {
+        let scripts = sprite.child("scripts").ok_or(ParseError::MissingElement { name: "scripts" })?;
+        scripts.elements().map(script_blocks).find(|x| x.first().is_some_and(|x| x.selector() == "receiveGo")).ok_or(ParseError::MissingElement { name: "receiveGo" })
+    }).collect::<Result<Vec<_>, _>>()?;
+    let preamble = |selector: &str| scripts.first().and_then(|x| x.iter().take(4).find(|x| x.selector() == selector)).map(|x| x.input(0)).transpose();
+
+    // the project notes hold the starting context, but the first sprite has it too
+    let title = notes.get("title").copied().or_else(|| root.attribute("name")).unwrap_or_default();
+    let tempo = match (notes.get("tempo"), preamble("setBPM")?) {
+        (Some(x), _) => Some(parse_tempo(x.parse().map_err(|_| invalid(x))?)),
+        (None, Some(x)) => Some(parse_tempo(parse_number(x)?)),
+        (None, None) => None,
+    };
+    let key = match (notes.get("key"), preamble("setKey")?) {
+        (Some(x), _) => Some(parse_key(x)?),
+        (None, Some(x)) => Some(parse_key(x.text().trim())?),
+        (None, None) => None,
+    };
+    let time_signature = notes.get("time signature").map(|x| parse_time_signature(x)).transpose()?;
+    let mut composition = Composition::new(title, tempo, key, time_signature);
+    let listed = |field: &str| notes.get(field).copied().filter(|x| *x != "N/A");
+    for x in listed("composers").into_iter().flat_map(|x| x.split(", ")) {
+        composition.add_composer(x);
+    }
+    for x in listed("lyricists").into_iter().flat_map(|x| x.split(", ")) {
+        composition.add_lyricist(x);
+    }
+    for x in listed("arrangers").into_iter().flat_map(|x| x.split(", ")) {
+        composition.add_arranger(x);
+    }
+    if let Some(x) = listed("publisher") {
+        composition.set_publisher(x);
+    }
+    if let Some(x) = listed("copyright") {
+        composition.set_copyright(x);
+    }
+    let tempo = quarter_note_tempo(composition.get_tempo());
+    let key = *composition.get_starting_key();
+
+    // each staff of a part is its own sprite named "{part}_{staff}"
+    let mut instruments = BTreeMap::new();
+    let mut parts: Vec<(String, Vec<SectionItem>)> = vec![];
+    for (sprite, blocks) in iter::zip(&sprites, &scripts) {
+        let sprite_name = sprite.attribute("name").unwrap_or_default();
+        let (part_name, staff_name) = sprite_name.rsplit_once('_').unwrap_or((sprite_name, sprite_name));
+
+        // a starting tempo or key that differs from the composition's is kept as a change at the start of the part
+        let mut start = 1;
+        for block in blocks.iter().skip(1).take(3) {
+            match block.selector() {
+                "setInstrument" => _ = instruments.insert(String::from(sprite_name), block.input(0)?.text()),
+                "setBPM" if parse_number(block.input(0)?)? == tempo => (),
+                "setKey" if parse_key(block.input(0)?.text().trim())? == key => (),
+                _ => break,
+            }
+            start += 1;
+        }
+
+        let mut state = State { definitions: &definitions, visiting: BTreeSet::new(), staff: staff_name.into(), sections: 0, note_mods: vec![], dynamic: None };
+        // part sections are either section blocks or (without them) the script content between them
+        let mut sections = vec![];
+        for (i, run) in blocks[start..].split(|x| x.name == "custom-block").enumerate() {
+            if !run.is_empty() {
+                let name = format!("{part_name} {}", i + 1);
+                let items = parse_blocks(run, &name, &mut state)?;
+                sections.push(SectionItem::Section(name, vec![], items));
+            }
+            if let Some(block) = blocks[start..].iter().filter(|x| x.name == "custom-block").nth(i) {
+                sections.extend(parse_blocks(&[block], part_name, &mut state)?);
+            }
+        }
+
+        match parts.iter_mut().find(|x| x.0 == part_name) {
+            Some((_, items)) => *items = merge_items(core::mem::take(items), sections),
+            None => parts.push((part_name.into(), sections)),
+        }
+    }
+
+    for (name, sections) in parts {
+        let part = composition.add_part(&name);
+        for section in sections {
+            match section {
+                SectionItem::Section(name, mods, items) => _ = part.claim_section(build_section(&name, mods, items)?),
+                SectionItem::Staff(name, items) => _ = part.claim_section(build_section(&name, vec![], vec![SectionItem::Staff(name.clone(), items)])?),
+            }
+        }
+    }
+
+    Ok(ParsedProject { composition, instruments })
+}
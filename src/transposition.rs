@@ -0,0 +1,76 @@
+use amm_sdk::note::{Note, Pitch, Accidental};
+use amm_sdk::context::Key;
+
+use crate::ornaments::LETTERS;
+
+const NATURAL_SEMITONES: [i16; 7] = [0, 2, 4, 5, 7, 9, 11];
+const INTERVAL_STEPS: [i8; 12] = [0, 1, 1, 2, 2, 3, 3, 4, 5, 5, 6, 6];
+
+// an interval to move every pitch (and key signature) by, as a number of letter names and the number of semitones they span
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Transposition {
+    pub steps: i8,
+    pub semitones: i8,
+}
+impl Transposition {
+    // spelled as the usual interval for the given number of semitones (e.g. 3 is a minor third rather than an augmented second)
+    pub fn semitones(semitones: i8) -> Self {
+        let steps = INTERVAL_STEPS[semitones.rem_euclid(12) as usize] + 7 * semitones.div_euclid(12);
+        Self { steps, semitones }
+    }
+    pub fn interval(steps: i8, semitones: i8) -> Self {
+        Self { steps, semitones }
+    }
+    pub fn then(self, other: Self) -> Self {
+        Self { steps: self.steps.saturating_add(other.steps), semitones: self.semitones.saturating_add(other.semitones) }
+    }
+
+    fn fifths(self) -> i16 {
+        7 * i16::from(self.semitones) - 12 * i16::from(self.steps)
+    }
+    // respelled enharmonically if the transposed key would need more than 7 sharps or flats
+    fn for_key(self, key: &Key) -> Self {
+        match i16::from(key.fifths()) + self.fifths() {
+            x if x > 7 => Self { steps: self.steps.saturating_add(1), ..self },
+            x if x < -7 => Self { steps: self.steps.saturating_sub(1), ..self },
+            _ => self,
+        }
+    }
+
+    pub(crate) fn key(self, key: &Key) -> Key {
+        let fifths = i16::from(key.fifths()) + self.for_key(key).fifths();
+        Key::from_fifths(fifths.clamp(-7, 7) as i8, Some(key.mode))
+    }
+    // notes without an accidental follow the (transposed) key, so only explicit accidentals need respelling
+    pub(crate) fn note(self, mut note: Note, key: &Key) -> Note {
+        if note.is_rest() || self == Self::default() {
+            return note;
+        }
+        let transposition = self.for_key(key);
+        let index = match LETTERS.iter().position(|x| *x == note.pitch.name) {
+            Some(x) => i16::from(note.pitch.octave) * 7 + x as i16,
+            None => return note,
+        };
+        let natural = |index: i16| index.div_euclid(7) * 12 + NATURAL_SEMITONES[index.rem_euclid(7) as usize];
+
+        let mut index = index + i16::from(transposition.steps);
+        if note.accidental != Accidental::None {
+            let target = natural(index - i16::from(transposition.steps)) + i16::from(note.accidental.value()) + i16::from(transposition.semitones);
+            while target - natural(index) > 2 {
+                index += 1;
+            }
+            while target - natural(index) < -2 {
+                index -= 1;
+            }
+            note.accidental = match target - natural(index) {
+                2 => Accidental::DoubleSharp,
+                1 => Accidental::Sharp,
+                -1 => Accidental::Flat,
+                -2 => Accidental::DoubleFlat,
+                _ => Accidental::Natural,
+            };
+        }
+        note.pitch = Pitch::new(LETTERS[index.rem_euclid(7) as usize], index.div_euclid(7).clamp(0, u8::MAX.into()) as u8);
+        note
+    }
+}
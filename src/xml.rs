@@ -0,0 +1,230 @@
+use core::fmt::{self, Write as _};
+
+use alloc::vec::Vec;
+use alloc::string::String;
+
+fn escape(input: &str, output: &mut impl fmt::Write) -> fmt::Result {
+    let mut rest = input;
+    while let Some(pos) = rest.find(['&', '<', '>', '\'', '"', '\n']) {
+        output.write_str(&rest[..pos])?;
+        output.write_str(match rest.as_bytes()[pos] {
+            b'&' => "&amp;",
+            b'<' => "&lt;",
+            b'>' => "&gt;",
+            b'\'' => "&apos;",
+            b'"' => "&quot;",
+            _ => "&#xD;",
+        })?;
+        rest = &rest[pos + 1..];
+    }
+    output.write_str(rest)
+}
+
+pub(crate) type Attributes<'a> = &'a [(&'static str, &'a dyn fmt::Display)];
+
+const INDENT: &str = "  ";
+
+// how many elements were open at some point, so that everything opened after it can be closed in one go
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Depth(usize);
+
+enum Output<'a> {
+    Buffer(String),
+    Sink(&'a mut dyn fmt::Write),
+    Discard,
+}
+impl Default for Output<'_> {
+    fn default() -> Self {
+        Output::Buffer(String::new())
+    }
+}
+
+// wraps the destination so that the first failed write is remembered and everything after it is skipped
+#[derive(Default)]
+struct Writer<'a> {
+    output: Output<'a>,
+    started: bool,
+    failed: bool,
+}
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.failed {
+            return Err(fmt::Error);
+        }
+        self.started |= !s.is_empty();
+        let res = match &mut self.output {
+            Output::Buffer(x) => x.write_str(s),
+            Output::Sink(x) => x.write_str(s),
+            Output::Discard => Ok(()),
+        };
+        self.failed = res.is_err();
+        res
+    }
+}
+
+// xml output that is always well-formed: text and attribute values are escaped, and elements are closed in the order they were opened.
+// the default writer buffers its output into a fragment, while sink writers stream straight into the destination.
+#[derive(Default)]
+pub(crate) struct Xml<'a> {
+    output: Writer<'a>,
+    open: Vec<&'static str>,
+    scratch: String,
+    pretty: bool,
+    closed: bool, // last thing written was a closing tag
+}
+impl<'a> Xml<'a> {
+    // in pretty mode, every element starts on its own line and elements with children end on their own line
+    pub(crate) fn new(output: &'a mut dyn fmt::Write, pretty: bool) -> Self {
+        Self { output: Writer { output: Output::Sink(output), ..Default::default() }, pretty, ..Default::default() }
+    }
+    // keeps track of structure like any other writer, but throws away the output
+    pub(crate) fn discard() -> Self {
+        Self { output: Writer { output: Output::Discard, ..Default::default() }, ..Default::default() }
+    }
+    fn newline(&mut self, depth: usize) {
+        if self.pretty && self.output.started {
+            let _ = self.output.write_char('\n');
+            for _ in 0..depth {
+                let _ = self.output.write_str(INDENT);
+            }
+        }
+    }
+    fn start_tag(&mut self, name: &'static str, attributes: Attributes) {
+        self.newline(self.open.len());
+        self.closed = false;
+        let _ = write!(self.output, "<{name}");
+        for (key, value) in attributes {
+            self.scratch.clear();
+            write!(self.scratch, "{value}").unwrap();
+            let _ = write!(self.output, r#" {key}=""#);
+            let _ = escape(&self.scratch, &mut self.output);
+            let _ = self.output.write_char('"');
+        }
+        let _ = self.output.write_char('>');
+    }
+    pub(crate) fn open(&mut self, name: &'static str, attributes: Attributes) -> &mut Self {
+        self.start_tag(name, attributes);
+        self.open.push(name);
+        self
+    }
+    pub(crate) fn close(&mut self) -> &mut Self {
+        let name = self.open.pop().expect("no open element to close");
+        if self.closed {
+            self.newline(self.open.len());
+        }
+        let _ = write!(self.output, "</{name}>");
+        self.closed = true;
+        self
+    }
+    pub(crate) fn depth(&self) -> Depth {
+        Depth(self.open.len())
+    }
+    // closes everything opened since the given depth, which must not have been closed already
+    pub(crate) fn close_to(&mut self, depth: Depth) -> &mut Self {
+        assert!(self.open.len() > depth.0, "nothing opened since depth {} is left to close: {:?}", depth.0, self.open);
+        while self.open.len() > depth.0 {
+            self.close();
+        }
+        self
+    }
+    // an element with nothing but text inside
+    pub(crate) fn leaf(&mut self, name: &'static str, attributes: Attributes, text: impl fmt::Display) -> &mut Self {
+        self.start_tag(name, attributes);
+        self.scratch.clear();
+        write!(self.scratch, "{text}").unwrap();
+        let _ = escape(&self.scratch, &mut self.output);
+        let _ = write!(self.output, "</{name}>");
+        self.closed = true;
+        self
+    }
+    pub(crate) fn empty(&mut self, name: &'static str, attributes: Attributes) -> &mut Self {
+        self.open(name, attributes).close()
+    }
+    pub(crate) fn append(&mut self, fragment: &Fragment) -> &mut Self {
+        if !self.pretty {
+            let _ = self.output.write_str(&fragment.0);
+            return self;
+        }
+
+        // fragments are always compact, so lay them out tag by tag (escaped text never contains '<' or '>')
+        let mut depth = self.open.len();
+        for token in fragment.0.split('<').skip(1) {
+            let (tag, text) = token.split_once('>').unwrap();
+            match tag.starts_with('/') {
+                true => {
+                    depth -= 1;
+                    if self.closed {
+                        self.newline(depth);
+                    }
+                    self.closed = true;
+                }
+                false => {
+                    self.newline(depth);
+                    depth += 1;
+                    self.closed = false;
+                }
+            }
+            let _ = write!(self.output, "<{tag}>{text}");
+        }
+        self
+    }
+
+    pub(crate) fn block(&mut self, selector: &str) -> &mut Self {
+        self.open("block", &[("s", &selector)])
+    }
+    pub(crate) fn l(&mut self, text: impl fmt::Display) -> &mut Self {
+        self.leaf("l", &[], text)
+    }
+
+    // whether every write so far has made it to the destination
+    pub(crate) fn status(&self) -> fmt::Result {
+        match self.output.failed {
+            true => Err(fmt::Error),
+            false => Ok(()),
+        }
+    }
+    pub(crate) fn finish(self) -> Fragment {
+        assert!(self.open.is_empty(), "unclosed elements: {:?}", self.open);
+        match self.output.output {
+            Output::Buffer(x) => Fragment(x),
+            Output::Sink(_) | Output::Discard => panic!("only buffered output can be turned into a fragment"),
+        }
+    }
+}
+
+// a finished (balanced) run of xml elements that can be held back, compared, and written out later
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Fragment(String);
+impl Fragment {
+    pub(crate) fn push(&mut self, other: &Fragment) {
+        self.0.push_str(&other.0);
+    }
+}
+impl From<Fragment> for String {
+    fn from(fragment: Fragment) -> Self {
+        fragment.0
+    }
+}
+
+// fnv-1a hash of everything written, for spotting identical output without keeping it around.
+// fnv is not collision resistant, so input crafted to make two different bodies collide would have one play in place of the other.
+// that risk is accepted so that block bodies never have to be held in memory; honest input colliding in 128 bits is not a concern.
+pub(crate) struct Digest(u128);
+impl Default for Digest {
+    fn default() -> Self {
+        Self(0x6c62272e07bb014262b821756295c58d)
+    }
+}
+impl Digest {
+    pub(crate) fn value(&self) -> u128 {
+        self.0
+    }
+}
+impl fmt::Write for Digest {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.0 = (self.0 ^ u128::from(b)).wrapping_mul(0x0000000001000000000000000000013b);
+        }
+        Ok(())
+    }
+}
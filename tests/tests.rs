@@ -1,7 +1,7 @@
 use amm_sdk::Composition;
 use amm_sdk::note::{DurationType, Duration, Accidental, Pitch, PitchName};
 use amm_sdk::context::{Dynamic, Key, Tempo, KeySignature, KeyMode, TimeSignature, TimeSignatureType, TempoSuggestion, TempoMarking};
-use amm_sdk::modification::{PhraseModificationType, NoteModificationType, SectionModificationType, DirectionType, ChordModificationType};
+use amm_sdk::modification::{PhraseModificationType, NoteModificationType, SectionModificationType, DirectionType, ChordModificationType, PedalType};
 
 use amm_sdk_netsblox::*;
 
@@ -758,3 +758,1089 @@ fn test_section_blocks() {
         panic!("{trans}");
     }
 }
+
+#[test]
+fn test_multivoice_2() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("Grand Piano");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let multivoice = staff.add_multivoice();
+
+        let voice = multivoice.add_phrase();
+        voice.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+        let note = voice.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 0), None);
+        note.add_modification(NoteModificationType::Staccato);
+
+        let voice = multivoice.add_phrase();
+        let note = voice.add_note(Pitch::new(PitchName::C, 3), Duration::new(DurationType::Half, 0), None);
+        note.add_modification(NoteModificationType::Accent);
+
+        staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/multivoice-2.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_multivoice_3() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("Choir");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        let phrase = staff.add_phrase();
+        let multivoice = phrase.add_multivoice();
+
+        let voice = multivoice.add_phrase();
+        voice.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Half, 0), None);
+
+        let voice = multivoice.add_phrase();
+        voice.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+        voice.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let voice = multivoice.add_phrase();
+        let inner = voice.add_multivoice();
+        inner.add_phrase().add_note(Pitch::new(PitchName::C, 3), Duration::new(DurationType::Half, 0), None);
+        inner.add_phrase().add_note(Pitch::new(PitchName::G, 2), Duration::new(DurationType::Half, 0), None);
+
+        phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Whole, 0), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/multivoice-3.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_grace_notes() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("Flute");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        let note = staff.add_note(Pitch::new(PitchName::D, 5), Duration::new(DurationType::Eighth, 0), None);
+        note.add_modification(NoteModificationType::Grace { acciaccatura: true });
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Quarter, 0), None);
+
+        let note = staff.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Eighth, 0), None);
+        note.add_modification(NoteModificationType::Grace { acciaccatura: false });
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None);
+
+        let note = staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Sixteenth, 0), None);
+        note.add_modification(NoteModificationType::Grace { acciaccatura: false });
+        let note = staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Sixteenth, 0), None);
+        note.add_modification(NoteModificationType::Grace { acciaccatura: false });
+        let note = staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Quarter, 1), None);
+        note.add_modification(NoteModificationType::Staccato);
+
+        let chord = staff.add_chord();
+        chord.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Grace { acciaccatura: true });
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Grace { acciaccatura: true });
+        let chord = staff.add_chord();
+        chord.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 0), None);
+        chord.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let note = staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Eighth, 0), None);
+        note.add_modification(NoteModificationType::Grace { acciaccatura: true });
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/grace-notes.xml") {
+        panic!("{trans}");
+    }
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_grace_notes(GraceNoteStyle::Appoggiatura)).unwrap();
+    if trans != include_str!("projects/grace-notes-appoggiatura.xml") {
+        panic!("{trans}");
+    }
+
+    // a principal note too short to borrow from leaves the grace note as written
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let staff = composition.add_part("Flute").add_section("sec0").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::D, 5), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Grace { acciaccatura: true });
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::SixtyFourth, 0), None).add_modification(NoteModificationType::Staccato);
+        composition
+    };
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert!(translation.project.contains(r#"<block s="playNotes"><l>Eighth</l><list><l>D5</l></list></block><block s="noteMod"><list><l><option>Staccato</option></l></list><script><block s="playNotes"><l>SixtyFourth</l><list><l>C5</l></list></block>"#));
+    assert_eq!(translation.warnings.iter().map(|x| &x.element).collect::<Vec<_>>(), [&IgnoredElement::NoteModification(NoteModificationType::Grace { acciaccatura: true })]);
+
+    // grace notes left without a principal note are played at face value, rounded like any other note unless durations must be exact
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let staff = composition.add_part("Flute").add_section("sec0").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::D, 5), Duration::new(DurationType::OneHundredTwentyEighth, 0), None).add_modification(NoteModificationType::Grace { acciaccatura: true });
+        composition
+    };
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert!(translation.project.contains(r#"<block s="playNotes"><l>SixtyFourth</l><list><l>D5</l></list></block>"#), "{}", translation.project);
+    assert_eq!(translation.warnings.iter().map(|x| &x.element).collect::<Vec<_>>(), [&IgnoredElement::Duration(Duration::new(DurationType::OneHundredTwentyEighth, 0))]);
+    let options = TranslateOptions::default().with_strict_durations(true);
+    assert!(matches!(translate_with_options(&composition, &options), Err(TranslateError::UnsupportedDuration { .. })));
+}
+
+#[test]
+fn test_warnings() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+
+        let staff = section.add_staff("staff0");
+        staff.add_direction(DirectionType::BreathMark);
+        let note = staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        note.add_modification(NoteModificationType::Pizzicato);
+        note.add_modification(NoteModificationType::Staccato);
+
+        let sub_section = section.add_section("sec1");
+        let staff = sub_section.add_staff("staff0");
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Pedal { pedal_type: PedalType::Sustain });
+        let chord = phrase.add_chord();
+        chord.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+        chord.add_modification(ChordModificationType::Arpeggiate);
+        chord.add_modification(ChordModificationType::Tenuto);
+
+        composition
+    };
+
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert_eq!(translation.project, translate(&composition).unwrap());
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::Direction(DirectionType::BreathMark) },
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::NoteModification(NoteModificationType::Pizzicato) },
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "sec1".into(), "staff0".into()], element: IgnoredElement::PhraseModification(PhraseModificationType::Pedal { pedal_type: PedalType::Sustain }) },
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "sec1".into(), "staff0".into()], element: IgnoredElement::ChordModification(ChordModificationType::Arpeggiate) },
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "sec1".into(), "staff0".into()], element: IgnoredElement::NoteModification(NoteModificationType::Tenuto) },
+    ]);
+    assert_eq!(translation.warnings[0].to_string(), "part0_staff0/sec0/staff0: ignored direction BreathMark");
+}
+
+#[test]
+fn test_options() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Accent);
+        let section = part.add_section("sec1");
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let part = composition.add_part("part1");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        composition
+    };
+
+    let options = TranslateOptions::default()
+        .with_room_name("Big Room")
+        .with_role_name("band")
+        .with_stage_size(800, 600)
+        .with_sprite_layout(SpriteLayout::Row)
+        .with_instrument("Violin")
+        .with_section_blocks(false);
+    let trans = translate_with_options(&composition, &options).unwrap();
+    if trans != include_str!("projects/options.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_instruments() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        for name in ["Trumpet", "Bass Clarinet", "Bassoon", "Electric Bass", "Violoncello", "Classical Guitar", "Piano Left Hand", "Flugelhorn", "Bass Guitar", "Electric Bass Guitar", "Bass Drum", "Kazoo", "Recorder", "Mystery"] {
+            let part = composition.add_part(name);
+            let section = part.add_section("sec0");
+            let staff = section.add_staff("staff0");
+            staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+        composition
+    };
+
+    let options = TranslateOptions::default()
+        .with_instrument_keyword("recorder", "Flute")
+        .with_part_instrument("Mystery", "Marimba")
+        .with_part_instrument("Kazoo_staff0", "Synthesizer");
+    let trans = translate_with_options(&composition, &options).unwrap();
+    if trans != include_str!("projects/instruments.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_midi_programs() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        for name in ["Track 1", "Track 2", "Track 3", "Violin"] {
+            let part = composition.add_part(name);
+            let section = part.add_section("sec0");
+            let staff = section.add_staff("staff0");
+            staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+        composition
+    };
+
+    let options = TranslateOptions::default()
+        .with_part_program("Track 1", 71)
+        .with_part_program("Track 2", 33)
+        .with_part_program("Track 3", 90);
+    let trans = translate_with_options(&composition, &options).unwrap();
+    if trans != include_str!("projects/midi-programs.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_ties() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+
+        let sub_section = section.add_section("sec0a");
+        let staff = sub_section.add_staff("staff0");
+
+        let note = staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        note.add_modification(NoteModificationType::Tie);
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Eighth, 0), None);
+
+        let note = staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Half, 0), None);
+        note.add_modification(NoteModificationType::Tie);
+        note.add_modification(NoteModificationType::Accent);
+        let note = staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 1), None);
+        note.add_modification(NoteModificationType::Tie);
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Sixteenth, 0), None);
+
+        let note = staff.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+        note.add_modification(NoteModificationType::Tie);
+        staff.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let chord = staff.add_chord();
+        chord.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Quarter, 0), None);
+        chord.add_modification(ChordModificationType::Tie);
+        let phrase = staff.add_phrase();
+        let chord = phrase.add_chord();
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Whole, 0), None);
+        chord.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Whole, 0), None);
+        phrase.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Tie);
+
+        let sub_section = section.add_section("sec0b");
+        let staff = sub_section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None);
+        staff.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Half, 0), None).add_modification(NoteModificationType::Tie);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/ties.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_legato() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("Cello");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 3), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Accent);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Legato);
+        phrase.add_note(Pitch::new(PitchName::D, 3), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Accent);
+        phrase.add_note(Pitch::new(PitchName::E, 3), Duration::new(DurationType::Quarter, 0), None);
+        phrase.add_note(Pitch::new(PitchName::F, 3), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Staccato);
+
+        let sub_phrase = phrase.add_phrase();
+        sub_phrase.add_modification(PhraseModificationType::Tuplet { num_beats: 3, into_beats: 2 });
+        sub_phrase.add_note(Pitch::new(PitchName::G, 3), Duration::new(DurationType::Eighth, 0), None);
+        sub_phrase.add_note(Pitch::new(PitchName::A, 3), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Staccato);
+        sub_phrase.add_note(Pitch::new(PitchName::B, 3), Duration::new(DurationType::Eighth, 0), None);
+
+        phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Half, 0), None);
+
+        staff.add_note(Pitch::new(PitchName::C, 3), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Staccato);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/legato.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_volume_ramps() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Dynamic { dynamic: Dynamic::Piano(1) });
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Crescendo { final_dynamic: Some(Dynamic::Forte(1)) });
+        for pitch in [PitchName::D, PitchName::E, PitchName::F, PitchName::G] {
+            phrase.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Decrescendo { final_dynamic: None });
+        phrase.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Half, 0), None);
+        phrase.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Half, 0), None);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Hairpin { maximum_dynamic: Some(Dynamic::Forte(2)) });
+        for pitch in [PitchName::C, PitchName::D, PitchName::E, PitchName::D, PitchName::C] {
+            phrase.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Whole, 0), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/volume-ramps.xml") {
+        panic!("{trans}");
+    }
+
+    // without a final dynamic, a crescendo heads for the dynamic that follows it
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let staff = composition.add_part("part0").add_section("sec0").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Dynamic { dynamic: Dynamic::Piano(1) });
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Crescendo { final_dynamic: None });
+        for pitch in [PitchName::D, PitchName::E, PitchName::F, PitchName::G] {
+            phrase.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None).add_modification(NoteModificationType::Dynamic { dynamic: Dynamic::Forte(1) });
+        composition
+    };
+    let trans = translate(&composition).unwrap();
+    let volumes = trans.split(r#"<l>Volume</l><l>"#).skip(1).map(|x| x.split('<').next().unwrap()).collect::<Vec<_>>();
+    assert_eq!(volumes, ["40", "43", "48", "53", "58", "60"]);
+}
+
+#[test]
+fn test_tempo_ramps() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_tempo(Tempo::new(Duration::new(DurationType::Quarter, 0), 120));
+        let part = composition.add_part("part0");
+
+        let section = part.add_section("sec0");
+        section.add_modification(SectionModificationType::Ritardando);
+        let staff = section.add_staff("staff0");
+        for pitch in [PitchName::C, PitchName::D, PitchName::E] {
+            staff.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+        staff.add_note(Pitch::new(PitchName::Rest, 0), Duration::new(DurationType::Quarter, 0), None);
+
+        let section = part.add_section("sec1");
+        section.add_modification(SectionModificationType::TempoExplicit { tempo: Tempo::new(Duration::new(DurationType::Quarter, 0), 80) });
+        section.add_modification(SectionModificationType::Accelerando);
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Half, 0), None);
+        staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Half, 0), None);
+
+        let section = part.add_section("sec2");
+        section.add_modification(SectionModificationType::Ritenuto);
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None);
+
+        let section = part.add_section("sec3");
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Whole, 0), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/tempo-ramps.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_measures() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_starting_time_signature(TimeSignature::new(TimeSignatureType::CommonTime));
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        for pitch in [PitchName::C, PitchName::D, PitchName::E, PitchName::F] {
+            staff.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+        staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Whole, 0), None).add_modification(NoteModificationType::Tie);
+        staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Half, 0), None);
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None);
+
+        staff.add_direction(DirectionType::TimeSignatureChange { time_signature: TimeSignature::new_explicit(3, 4) });
+        staff.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Half, 1), None);
+        staff.add_note(Pitch::new(PitchName::Rest, 0), Duration::new(DurationType::Quarter, 0), None);
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Quarter, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 5), Duration::new(DurationType::Quarter, 0), None);
+
+        staff.add_direction(DirectionType::TimeSignatureChange { time_signature: TimeSignature::new_explicit(3, 4) });
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Half, 1), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/measures.xml") {
+        panic!("{trans}");
+    }
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_measure_numbers(true)).unwrap();
+    if trans != include_str!("projects/measures-numbered.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_repeat_endings() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        section.add_modification(SectionModificationType::Repeat { num_times: 2 });
+
+        let body = section.add_section("body");
+        let staff = body.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Half, 0), None);
+
+        let inner = body.add_section("inner");
+        inner.add_modification(SectionModificationType::Repeat { num_times: 1 });
+        let staff = inner.add_section("inner body").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None);
+        let ending = inner.add_section("inner ending");
+        ending.add_modification(SectionModificationType::OnlyPlay { iterations: vec![2] });
+        ending.add_staff("staff0").add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let ending = section.add_section("ending 1");
+        ending.add_modification(SectionModificationType::OnlyPlay { iterations: vec![1, 2] });
+        let staff = ending.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Whole, 0), None).add_modification(NoteModificationType::Staccato);
+
+        let ending = section.add_section("ending 2");
+        ending.add_modification(SectionModificationType::OnlyPlay { iterations: vec![3] });
+        let staff = ending.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Whole, 0), None);
+
+        let section = part.add_section("sec1");
+        section.add_modification(SectionModificationType::OnlyPlay { iterations: vec![1] });
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Whole, 0), None);
+
+        composition
+    };
+
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    if translation.project != include_str!("projects/repeat-endings.xml") {
+        panic!("{}", translation.project);
+    }
+    // outside of any repeat there is nothing to guard an ending with, so it always plays
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec1".into()], element: IgnoredElement::SectionModification(SectionModificationType::OnlyPlay { iterations: vec![1] }) },
+    ]);
+}
+
+#[test]
+fn test_fermatas() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_tempo(Tempo::new(Duration::new(DurationType::Quarter, 0), 90));
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Fermata);
+        staff.add_note(Pitch::new(PitchName::Rest, 0), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Fermata);
+
+        let chord = staff.add_chord();
+        chord.add_modification(ChordModificationType::Fermata);
+        chord.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Half, 1), None);
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Half, 1), None);
+
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Whole, 0), None).add_modification(NoteModificationType::Tie);
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Fermata);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/fermatas.xml") {
+        panic!("{trans}");
+    }
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_fermata_factor(1.5)).unwrap();
+    if trans != include_str!("projects/fermatas-1.5.xml") {
+        panic!("{trans}");
+    }
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_fermata_style(FermataStyle::Wait)).unwrap();
+    if trans != include_str!("projects/fermatas-wait.xml") {
+        panic!("{trans}");
+    }
+}
+
+#[test]
+fn test_ornaments() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_starting_key(Key::new(KeySignature::G, KeyMode::Major));
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Trill { upper: true });
+        staff.add_note(Pitch::new(PitchName::E, 5), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Trill { upper: true });
+        staff.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Eighth, 0), Some(Accidental::Natural)).add_modification(NoteModificationType::Mordent { upper: true });
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Mordent { upper: false });
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::SixtyFourth, 0), None).add_modification(NoteModificationType::Trill { upper: true });
+
+        let chord = staff.add_chord();
+        chord.add_modification(ChordModificationType::Accent);
+        chord.add_note(Pitch::new(PitchName::B, 3), Duration::new(DurationType::Sixteenth, 0), None);
+        chord.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Sixteenth, 0), None).add_modification(NoteModificationType::Trill { upper: false });
+
+        staff.add_direction(DirectionType::KeyChange { key: Key::new(KeySignature::F, KeyMode::Major) });
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None).add_modification(NoteModificationType::Trill { upper: true });
+
+        composition
+    };
+
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    if translation.project != include_str!("projects/ornaments.xml") {
+        panic!("{}", translation.project);
+    }
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::NoteModification(NoteModificationType::Trill { upper: true }) },
+    ]);
+}
+
+#[test]
+fn test_tremolos() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Tremolo { relative_speed: 2 });
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 1), None).add_modification(NoteModificationType::Tremolo { relative_speed: 1 });
+
+        let chord = staff.add_chord();
+        chord.add_modification(ChordModificationType::Accent);
+        chord.add_modification(ChordModificationType::Tremolo { relative_speed: 3 });
+        chord.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Eighth, 0), None);
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Eighth, 0), None);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Tremolo { relative_speed: 2 });
+        phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Dynamic { dynamic: Dynamic::Piano(1) });
+        phrase.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), Some(Accidental::Flat));
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Tremolo { relative_speed: 1 });
+        phrase.add_note(Pitch::new(PitchName::G, 3), Duration::new(DurationType::Quarter, 0), None);
+        phrase.add_note(Pitch::new(PitchName::B, 3), Duration::new(DurationType::Eighth, 1), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/tremolos.xml") {
+        panic!("{trans}");
+    }
+
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let staff = composition.add_part("part0").add_section("sec0").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Tremolo { relative_speed: 5 });
+        composition
+    };
+    assert!(matches!(translate(&composition), Err(TranslateError::UnsupportedTremolo { relative_speed: 5 })));
+
+    // too short to split into strokes, so it is played as written
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let staff = composition.add_part("part0").add_section("sec0").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Sixteenth, 0), None).add_modification(NoteModificationType::Tremolo { relative_speed: 2 });
+        composition
+    };
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert_eq!(translation.project.matches("<l>C4</l>").count(), 1);
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::NoteModification(NoteModificationType::Tremolo { relative_speed: 2 }) },
+    ]);
+}
+#[test]
+fn test_glissandos() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_starting_key(Key::new(KeySignature::D, KeyMode::Major));
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), Some(Accidental::Natural)).add_modification(NoteModificationType::Glissando { from_current: true, going_up: true });
+        staff.add_direction(DirectionType::BreathMark);
+        staff.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Glissando { from_current: false, going_up: true });
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Portamento);
+        phrase.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Half, 0), None);
+        phrase.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Eighth, 0), None);
+        phrase.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Eighth, 0), None);
+
+        let chord = staff.add_chord();
+        chord.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Portamento { from_current: true, going_up: false });
+        chord.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 5), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Glissando { from_current: true, going_up: true });
+
+        composition
+    };
+
+    let translation = translate_with_report(&composition, &TranslateOptions::default().with_glissandos(GlissandoStyle::Chromatic)).unwrap();
+    if translation.project != include_str!("projects/glissandos.xml") {
+        panic!("{}", translation.project);
+    }
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::Direction(DirectionType::BreathMark) },
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::PhraseModification(PhraseModificationType::Portamento) },
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::NoteModification(NoteModificationType::Glissando { from_current: true, going_up: true }) },
+    ]);
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_glissandos(GlissandoStyle::Diatonic)).unwrap();
+    if trans != include_str!("projects/glissandos-diatonic.xml") {
+        panic!("{trans}");
+    }
+
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert_eq!(translation.warnings.len(), 6);
+}
+#[test]
+fn test_octave_shifts() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::OctaveShift { num_octaves: 1 });
+        phrase.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+        let chord = phrase.add_chord();
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Quarter, 0), None);
+        chord.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Quarter, 0), Some(Accidental::Flat));
+        phrase.add_note(Pitch::new_rest(), Duration::new(DurationType::Quarter, 0), None);
+
+        let inner = phrase.add_phrase();
+        inner.add_modification(PhraseModificationType::OctaveShift { num_octaves: 1 });
+        inner.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Mordent { upper: true });
+        let inner = inner.add_phrase();
+        inner.add_modification(PhraseModificationType::OctaveShift { num_octaves: -3 });
+        inner.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        phrase.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::OctaveShift { num_octaves: -1 });
+        phrase.add_note(Pitch::new(PitchName::A, 3), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Grace { acciaccatura: true });
+        phrase.add_note(Pitch::new(PitchName::G, 3), Duration::new(DurationType::Half, 0), None);
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        composition
+    };
+
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    if translation.project != include_str!("projects/octave-shifts.xml") {
+        panic!("{}", translation.project);
+    }
+    assert_eq!(translation.warnings, []);
+}
+#[test]
+fn test_transposition() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_starting_key(Key::new(KeySignature::F, KeyMode::Major));
+        for name in ["Clarinet in Bb", "Flute"] {
+            let part = composition.add_part(name);
+            let section = part.add_section("sec0");
+            let staff = section.add_staff("staff0");
+
+            staff.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 0), None);
+            staff.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Quarter, 0), None);
+            staff.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Quarter, 0), Some(Accidental::Natural));
+            staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Quarter, 0), Some(Accidental::Sharp)).add_modification(NoteModificationType::Trill { upper: true });
+            let chord = staff.add_chord();
+            chord.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Half, 0), Some(Accidental::Flat));
+            chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Half, 0), None);
+
+            staff.add_direction(DirectionType::KeyChange { key: Key::new(KeySignature::CSharp, KeyMode::Major) });
+            staff.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+            staff.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Quarter, 0), Some(Accidental::DoubleSharp));
+        }
+        composition
+    };
+
+    let options = TranslateOptions::default().with_part_transposition("Clarinet in Bb", Transposition::semitones(-2));
+    let trans = translate_with_options(&composition, &options).unwrap();
+    if trans != include_str!("projects/transposition-part.xml") {
+        panic!("{trans}");
+    }
+
+    let trans = translate_with_options(&composition, &options.with_transposition(Transposition::semitones(2))).unwrap();
+    if trans != include_str!("projects/transposition-all.xml") {
+        panic!("{trans}");
+    }
+
+    assert_eq!(Transposition::semitones(-2), Transposition::interval(-1, -2));
+    assert_eq!(Transposition::semitones(15), Transposition::interval(9, 15));
+}
+#[test]
+fn test_scaled_tuplets() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        composition.set_starting_time_signature(TimeSignature::new_explicit(6, 8));
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Tuplet { num_beats: 2, into_beats: 3 });
+        phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Eighth, 0), None).add_modification(NoteModificationType::Tie);
+        phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Eighth, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 1), None);
+
+        let phrase = staff.add_phrase();
+        phrase.add_modification(PhraseModificationType::Tuplet { num_beats: 5, into_beats: 4 });
+        let inner = phrase.add_phrase();
+        inner.add_modification(PhraseModificationType::Tuplet { num_beats: 9, into_beats: 8 });
+        for _ in 0..9 {
+            inner.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::ThirtySecond, 0), None);
+        }
+        let inner = phrase.add_phrase();
+        inner.add_modification(PhraseModificationType::Tuplet { num_beats: 3, into_beats: 2 });
+        for _ in 0..3 {
+            inner.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Sixteenth, 0), None);
+        }
+        phrase.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::Eighth, 0), None);
+        staff.add_note(Pitch::new(PitchName::A, 4), Duration::new(DurationType::Quarter, 0), None);
+
+        composition
+    };
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_measure_numbers(true)).unwrap();
+    if trans != include_str!("projects/scaled-tuplets.xml") {
+        panic!("{trans}");
+    }
+
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let phrase = composition.add_part("part0").add_section("sec0").add_staff("staff0").add_phrase();
+        phrase.add_modification(PhraseModificationType::Tuplet { num_beats: 0, into_beats: 2 });
+        phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Eighth, 0), None);
+        composition
+    };
+    assert!(matches!(translate(&composition), Err(TranslateError::UnsupportedTuplet { num_beats: 0, into_beats: 2 })));
+
+    // voices share the tempo, so they play these as written
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let multivoice = composition.add_part("part0").add_section("sec0").add_staff("staff0").add_multivoice();
+        let voice = multivoice.add_phrase();
+        voice.add_modification(PhraseModificationType::Tuplet { num_beats: 2, into_beats: 3 });
+        voice.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        voice.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None);
+        multivoice.add_phrase().add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Half, 0), None);
+        composition
+    };
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert_eq!(translation.project.matches("setBPM").count(), 1);
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::PhraseModification(PhraseModificationType::Tuplet { num_beats: 2, into_beats: 3 }) },
+    ]);
+}
+#[test]
+fn test_short_durations() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("part0");
+        let section = part.add_section("sec0");
+        let staff = section.add_staff("staff0");
+
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::OneHundredTwentyEighth, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::OneHundredTwentyEighth, 0), None);
+        staff.add_note(Pitch::new_rest(), Duration::new(DurationType::TwoHundredFiftySixth, 0), None);
+        staff.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::SixtyFourth, 3), None);
+        staff.add_note(Pitch::new(PitchName::F, 4), Duration::new(DurationType::Quarter, 6), None);
+        let chord = staff.add_chord();
+        chord.add_note(Pitch::new(PitchName::G, 4), Duration::new(DurationType::OneHundredTwentyEighth, 1), None);
+        chord.add_note(Pitch::new(PitchName::B, 4), Duration::new(DurationType::Eighth, 0), None);
+        staff.add_note(Pitch::new(PitchName::C, 5), Duration::new(DurationType::Quarter, 0), None);
+
+        composition
+    };
+
+    let trans = translate(&composition).unwrap();
+    if trans != include_str!("projects/short-durations.xml") {
+        panic!("{trans}");
+    }
+
+    let options = TranslateOptions::default().with_strict_durations(true);
+    assert!(matches!(translate_with_options(&composition, &options), Err(TranslateError::UnsupportedDuration { duration: Duration { value: DurationType::OneHundredTwentyEighth, dots: 0 } })));
+
+    // the real tempo is restored before a repeat goes back to its start
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let section = composition.add_part("part0").add_section("sec0");
+        section.add_modification(SectionModificationType::Repeat { num_times: 1 });
+        let staff = section.add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::OneHundredTwentyEighth, 0), None);
+        composition
+    };
+    let trans = translate(&composition).unwrap();
+    assert!(trans.contains(r#"<block s="setBPM"><l>120</l></block></script></block></script>"#), "{trans}");
+
+    // voices share the tempo, so inside a fork these are held for the nearest 64th note instead
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let multivoice = composition.add_part("part0").add_section("sec0").add_staff("staff0").add_multivoice();
+        let voice = multivoice.add_phrase();
+        voice.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::OneHundredTwentyEighth, 0), None);
+        voice.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Quarter, 0), None);
+        multivoice.add_phrase().add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Half, 0), None);
+        composition
+    };
+    let translation = translate_with_report(&composition, &Default::default()).unwrap();
+    assert_eq!(translation.project.matches("setBPM").count(), 1);
+    assert!(translation.project.contains("<l>SixtyFourth</l><list><l>C4</l></list>"), "{}", translation.project);
+    assert_eq!(translation.warnings, [
+        TranslateWarning { path: vec!["part0_staff0".into(), "sec0".into(), "staff0".into()], element: IgnoredElement::Duration(Duration::new(DurationType::OneHundredTwentyEighth, 0)) },
+    ]);
+}
+
+#[test]
+fn test_parse_project() {
+    // projects written with the default options translate back into themselves
+    for project in [
+        include_str!("projects/notes.xml"), include_str!("projects/chords.xml"), include_str!("projects/note-mods.xml"), include_str!("projects/dynamics.xml"),
+        include_str!("projects/tuplets.xml"), include_str!("projects/repeat.xml"), include_str!("projects/repeat-endings.xml"), include_str!("projects/rests.xml"),
+        include_str!("projects/accidentals.xml"), include_str!("projects/tempo.xml"), include_str!("projects/section-blocks.xml"), include_str!("projects/multivoice-3.xml"),
+        include_str!("projects/ties.xml"), include_str!("projects/legato.xml"), include_str!("projects/measures.xml"), include_str!("projects/mods-stack-2.xml"),
+    ] {
+        let trans = translate(&parse_project(project).unwrap().composition).unwrap();
+        if trans != project {
+            panic!("{trans}");
+        }
+    }
+
+    let composition = {
+        let mut composition = Composition::new("two hands", None, None, None);
+        composition.add_composer("someone");
+        composition.set_copyright("nobody & co");
+        let part = composition.add_part("piano");
+        let section = part.add_section("sec0");
+        section.add_modification(SectionModificationType::Repeat { num_times: 1 });
+        let staff = section.add_staff("right");
+        staff.add_note(Pitch::new(PitchName::E, 5), Duration::new(DurationType::Half, 0), None);
+        staff.add_note(Pitch::new(PitchName::D, 5), Duration::new(DurationType::Half, 0), Some(Accidental::Sharp));
+        let staff = section.add_staff("left");
+        staff.add_note(Pitch::new(PitchName::C, 3), Duration::new(DurationType::Whole, 0), None);
+        let staff = part.add_section("sec1").add_staff("left");
+        staff.add_note(Pitch::new(PitchName::G, 2), Duration::new(DurationType::Whole, 0), None);
+        composition
+    };
+    let trans = translate(&composition).unwrap();
+    let parsed = parse_project(&trans).unwrap();
+    assert_eq!(parsed.composition.get_title(), "two hands");
+    assert_eq!(parsed.composition.get_composers(), ["someone"]);
+    assert_eq!(parsed.composition.get_copyright().as_deref(), Some("nobody & co"));
+    assert_eq!(parsed.composition.get_part_names(), ["piano"]);
+    assert_eq!(parsed.composition.get_part_by_name("piano").unwrap().get_staff_names(), ["left", "right"]);
+    assert_eq!(translate(&parsed.composition).unwrap(), trans);
+    let options = TranslateOptions::default().with_section_blocks(false);
+    let trans = translate_with_options(&composition, &options).unwrap();
+    assert_eq!(translate_with_options(&parse_project(&trans).unwrap().composition, &options).unwrap(), trans);
+
+    let project = include_str!("projects/instruments.xml");
+    let parsed = parse_project(project).unwrap();
+    assert_eq!(parsed.instruments.get("Kazoo_staff0").map(String::as_str), Some("Synthesizer"));
+    let options = parsed.instruments.iter().fold(TranslateOptions::default(), |options, (sprite, instrument)| options.with_part_instrument(sprite.clone(), instrument.clone()));
+    assert_eq!(translate_with_options(&parsed.composition, &options).unwrap(), project);
+
+    // every tempo change starts a new section, which must not nest any deeper than the one before it
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let staff = composition.add_part("part0").add_section("sec0").add_staff("staff0");
+        for _ in 0..2000 {
+            let phrase = staff.add_phrase();
+            phrase.add_modification(PhraseModificationType::Tuplet { num_beats: 2, into_beats: 3 });
+            phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Eighth, 0), None);
+            phrase.add_note(Pitch::new(PitchName::D, 4), Duration::new(DurationType::Eighth, 0), None);
+        }
+        composition
+    };
+    let tempos = translate(&composition).unwrap();
+    assert_eq!(tempos.matches("setBPM").count(), 4001);
+    assert_eq!(translate(&parse_project(&tempos).unwrap().composition).unwrap(), tempos);
+
+    assert!(matches!(parse_project("<room><role>"), Err(ParseError::InvalidXml { .. })));
+    assert!(matches!(parse_project("<room></room>"), Err(ParseError::MissingElement { name: "project" })));
+    assert!(matches!(parse_project(&trans.replacen("playNotes", "doSayFor", 1)), Err(ParseError::UnsupportedBlock { selector }) if selector == "doSayFor"));
+    assert!(matches!(parse_project(&trans.replacen("E5", "H5", 1)), Err(ParseError::InvalidValue { value }) if value == "H5"));
+    let voices = include_str!("projects/multivoice-3.xml").replacen(r#"reifyScript"><script>"#, r#"reifyScript"><script><block s="setKey"><l>GMajor</l></block>"#, 1);
+    assert!(matches!(parse_project(&voices), Err(ParseError::UnsupportedDirection { direction: DirectionType::KeyChange { .. } })));
+}
+#[test]
+fn test_escaping() {
+    let composition = {
+        let mut composition = Composition::new("<b>\"tom\" & 'jerry'</b>", None, None, None);
+        let part = composition.add_part("cat & mouse");
+        let staff = part.add_section("chase <1>").add_staff("staff0");
+        staff.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Whole, 0), None);
+        composition
+    };
+    let trans = translate(&composition).unwrap();
+    assert!(trans.starts_with(r#"<room name="&lt;b&gt;&quot;tom&quot; &amp; &apos;jerry&apos;&lt;/b&gt;">"#));
+    assert!(trans.contains(r#"<sprite name="cat &amp; mouse_staff0" "#));
+    assert!(trans.contains(r#"<custom-block s="chase &lt;1&gt;"></custom-block>"#));
+    let parsed = parse_project(&trans).unwrap();
+    assert_eq!(parsed.composition.get_title(), composition.get_title());
+    assert_eq!(parsed.composition.get_part_names(), ["cat & mouse"]);
+    assert_eq!(translate(&parsed.composition).unwrap(), trans);
+}
+#[test]
+fn test_pretty() {
+    let project = include_str!("projects/note-mods.xml");
+    let composition = parse_project(project).unwrap().composition;
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_pretty(true)).unwrap();
+    if trans != include_str!("projects/note-mods-pretty.xml") {
+        panic!("{trans}");
+    }
+    assert_eq!(trans.lines().map(str::trim_start).collect::<String>(), project);
+    assert_eq!(translate(&parse_project(&trans).unwrap().composition).unwrap(), project);
+}
+#[test]
+#[cfg(feature = "std")]
+fn test_translate_into() {
+    struct Limited(String, usize);
+    impl core::fmt::Write for Limited {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            if self.0.len() + s.len() > self.1 {
+                return Err(core::fmt::Error);
+            }
+            self.0.push_str(s);
+            Ok(())
+        }
+    }
+
+    let project = include_str!("projects/instruments.xml");
+    let composition = parse_project(project).unwrap().composition;
+    let trans = translate(&composition).unwrap();
+    let mut output = Limited(String::new(), usize::MAX);
+    assert_eq!(translate_into(&composition, &TranslateOptions::default(), &mut output).unwrap(), translate_with_report(&composition, &TranslateOptions::default()).unwrap().warnings);
+    assert_eq!(output.0, trans);
+
+    for limit in [0, 100, trans.len() / 2, trans.len() - 1] {
+        let mut output = Limited(String::new(), limit);
+        assert!(matches!(translate_into(&composition, &TranslateOptions::default(), &mut output), Err(TranslateError::WriteFailed)));
+        assert!(trans.starts_with(&output.0));
+    }
+
+    let mut output = IoWriter::new(Vec::new());
+    translate_into(&composition, &TranslateOptions::default().with_pretty(true), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output.into_inner()).unwrap(), translate_with_options(&composition, &TranslateOptions::default().with_pretty(true)).unwrap());
+
+    // streaming still writes block definitions in order of name
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        for (part, section, pitch) in [("part0", "verse", PitchName::C), ("part1", "chorus", PitchName::D), ("part2", "bridge", PitchName::E)] {
+            composition.add_part(part).add_section(section).add_staff("staff0").add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Quarter, 0), None);
+        }
+        composition
+    };
+    let trans = translate(&composition).unwrap();
+    let order = ["bridge", "chorus", "verse"].map(|x| trans.find(&format!(r#"<block-definition s="{x}""#)).unwrap());
+    assert!(order.is_sorted(), "{trans}");
+}
+#[test]
+fn test_shared_blocks() {
+    let composition = {
+        let mut composition = Composition::new("untitled", None, None, None);
+        let part = composition.add_part("Voice");
+        for name in ["chorus", "verse", "chorus"] {
+            let staff = part.add_section(name).add_staff("staff0");
+            for _ in 0..2 {
+                let phrase = staff.add_phrase();
+                phrase.add_modification(PhraseModificationType::Legato);
+                phrase.add_note(Pitch::new(PitchName::C, 4), Duration::new(DurationType::Quarter, 0), None).add_modification(NoteModificationType::Accent);
+                phrase.add_note(Pitch::new(PitchName::E, 4), Duration::new(DurationType::Quarter, 0), None);
+            }
+            let pitch = if name == "verse" { PitchName::F } else { PitchName::G };
+            staff.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Half, 0), None).add_modification(NoteModificationType::Tie);
+            let phrase = staff.add_phrase();
+            phrase.add_note(Pitch::new(pitch, 4), Duration::new(DurationType::Half, 0), None).add_modification(NoteModificationType::Tie);
+        }
+        composition
+    };
+    let trans = translate(&composition).unwrap();
+    assert_eq!(trans.matches(r#"<custom-block s="chorus">"#).count(), 2);
+    assert_eq!(trans.matches("<block-definition ").count(), 2);
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_phrase_blocks(true)).unwrap();
+    if trans != include_str!("projects/phrase-blocks.xml") {
+        panic!("{trans}");
+    }
+    assert_eq!(trans.matches(r#"<custom-block s="phrase">"#).count(), 4);
+    assert_eq!(trans.matches("<block-definition ").count(), 3);
+    assert_eq!(parse_project(&trans).unwrap().composition.get_part_by_name("Voice").unwrap().iter().count(), 3);
+
+    let trans = translate_with_options(&composition, &TranslateOptions::default().with_phrase_blocks(true).with_section_blocks(false)).unwrap();
+    assert_eq!(trans.matches(r#"<custom-block s="phrase">"#).count(), 6);
+    assert_eq!(trans.matches("<block-definition ").count(), 1);
+}